@@ -0,0 +1,494 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use syntect::util::LinesWithEndings;
+
+use crate::{
+    palette::{Scheme, resolve},
+    util::escape_html,
+};
+
+const COLOUR_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+// xterm defaults, used to fold 256-colour and truecolour codes onto the sixteen classes
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+static ESCAPE_NOTATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\\e\[|\\x1[bB]\[|\\033\[|\^\[\[|\\u001[bB]\[").expect("compile time regex invalid")
+});
+
+static PROMPT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?:PS [^>]*>|(?:\([\w.-]+\) )?(?:[\w.-]+@[\w.-]+(?::\S*)?)?[$#%]|[\w-]+(?: [\w-]+\([^)]*\))? >) ",
+    )
+    .expect("compile time regex invalid")
+});
+
+pub fn colour_class(prefix: &str, colour: u8) -> String {
+    let name = COLOUR_NAMES[usize::from(colour % 8)];
+    match colour {
+        0..=7 => format!("{prefix}-{name}"),
+        _ => format!("{prefix}-bright-{name}"),
+    }
+}
+
+// shared by pages and svgs; the sixteen colours are --ansi-* properties in base.css, so each scheme can adjust them
+fn rules() -> Vec<(String, Vec<(&'static str, String)>)> {
+    let rule = |class: &str, declarations: &[(&'static str, &str)]| {
+        (
+            class.to_string(),
+            declarations
+                .iter()
+                .map(|(property, value)| (*property, value.to_string()))
+                .collect::<Vec<_>>(),
+        )
+    };
+    let mut rules = vec![
+        rule("ansi-prompt", &[("color", "var(--lighttextcolor)")]),
+        rule("ansi-command", &[("font-weight", "bold")]),
+        rule("ansi-bold", &[("font-weight", "bold")]),
+        rule("ansi-dim", &[("opacity", "0.6")]),
+        rule("ansi-italic", &[("font-style", "italic")]),
+        rule("ansi-underline", &[("text-decoration", "underline")]),
+        rule("ansi-strike", &[("text-decoration", "line-through")]),
+        rule(
+            "ansi-inverse",
+            &[
+                ("color", "var(--box-fill)"),
+                ("background", "var(--textcolor)"),
+            ],
+        ),
+    ];
+    for colour in 0..16 {
+        let value = format!("var({})", colour_class("--ansi", colour));
+        rules.push(rule(&colour_class("ansi-fg", colour), &[("color", &value)]));
+        rules.push(rule(
+            &colour_class("ansi-bg", colour),
+            &[("background", &value)],
+        ));
+    }
+    rules
+}
+
+pub fn html_css() -> String {
+    rules()
+        .iter()
+        .map(|(class, declarations)| {
+            format!(
+                "pre .{class} {{\n{}}}\n\n",
+                declarations
+                    .iter()
+                    .map(|(property, value)| format!("    {property}: {value};\n"))
+                    .collect::<String>()
+            )
+        })
+        .collect()
+}
+
+// svg text is coloured by its fill and has no background, so backgrounds fill the rects drawn behind it
+pub fn svg_css() -> String {
+    let mut css = String::new();
+    for (class, declarations) in rules() {
+        for (property, value) in declarations {
+            let value = resolve(&value, Scheme::Dark).expect("base.css declares every ansi colour");
+            match property {
+                "color" => css.push_str(&format!(".{class}{{fill:{value}}}")),
+                "background" => css.push_str(&format!("rect.{class}{{fill:{value}}}")),
+                _ => css.push_str(&format!(".{class}{{{property}:{value}}}")),
+            }
+        }
+    }
+    css
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Style {
+    foreground: Option<u8>,
+    background: Option<u8>,
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    inverse: bool,
+    strike: bool,
+}
+
+impl Style {
+    pub fn classes(&self) -> String {
+        let (foreground, background) = if self.inverse {
+            (self.background, self.foreground)
+        } else {
+            (self.foreground, self.background)
+        };
+        let mut classes = Vec::new();
+        for (flag, class) in [
+            (self.inverse, "ansi-inverse"),
+            (self.bold, "ansi-bold"),
+            (self.dim, "ansi-dim"),
+            (self.italic, "ansi-italic"),
+            (self.underline, "ansi-underline"),
+            (self.strike, "ansi-strike"),
+        ] {
+            if flag {
+                classes.push(class.to_string());
+            }
+        }
+        for (colour, prefix) in [(foreground, "ansi-fg"), (background, "ansi-bg")] {
+            if let Some(colour) = colour {
+                classes.push(colour_class(prefix, colour));
+            }
+        }
+        classes.join(" ")
+    }
+
+    pub fn background_class(&self) -> Option<String> {
+        let background = if self.inverse {
+            self.foreground
+        } else {
+            self.background
+        };
+        match background {
+            Some(colour) => Some(colour_class("ansi-bg", colour)),
+            None => self.inverse.then(|| String::from("ansi-inverse")),
+        }
+    }
+
+    pub fn apply(&mut self, parameters: &str) {
+        let mut codes = parameters
+            .split([';', ':'])
+            .map(|code| code.parse::<u16>().unwrap_or(0));
+        while let Some(code) = codes.next() {
+            match code {
+                0 => *self = Self::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                7 => self.inverse = true,
+                9 => self.strike = true,
+                22 => (self.bold, self.dim) = (false, false),
+                23 => self.italic = false,
+                24 => self.underline = false,
+                27 => self.inverse = false,
+                29 => self.strike = false,
+                30..=37 => self.foreground = Some((code - 30) as u8),
+                38 => self.foreground = extended_colour(&mut codes),
+                39 => self.foreground = None,
+                40..=47 => self.background = Some((code - 40) as u8),
+                48 => self.background = extended_colour(&mut codes),
+                49 => self.background = None,
+                90..=97 => self.foreground = Some((code - 90 + 8) as u8),
+                100..=107 => self.background = Some((code - 100 + 8) as u8),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn nearest(red: u8, green: u8, blue: u8) -> u8 {
+    let distance = |(r, g, b): (u8, u8, u8)| {
+        [(r, red), (g, green), (b, blue)]
+            .iter()
+            .map(|(a, b)| (i32::from(*a) - i32::from(*b)).pow(2))
+            .sum::<i32>()
+    };
+    (0..16u8)
+        .min_by_key(|index| distance(PALETTE[usize::from(*index)]))
+        .unwrap_or(7)
+}
+
+fn extended_colour(codes: &mut impl Iterator<Item = u16>) -> Option<u8> {
+    let channel = |value: Option<u16>| value.unwrap_or(0).min(255) as u8;
+    match codes.next() {
+        Some(5) => match codes.next().map(|index| index.min(255) as u8) {
+            Some(index @ 0..=15) => Some(index),
+            Some(index @ 16..=231) => {
+                let cube = |level: u8| if level == 0 { 0 } else { 55 + level * 40 };
+                let index = index - 16;
+                Some(nearest(
+                    cube(index / 36),
+                    cube(index / 6 % 6),
+                    cube(index % 6),
+                ))
+            }
+            Some(index) => {
+                let grey = 8 + (index - 232) * 10;
+                Some(nearest(grey, grey, grey))
+            }
+            None => None,
+        },
+        Some(2) => {
+            let (red, green, blue) = (
+                channel(codes.next()),
+                channel(codes.next()),
+                channel(codes.next()),
+            );
+            Some(nearest(red, green, blue))
+        }
+        _ => None,
+    }
+}
+
+pub enum Sequence {
+    Text(char),
+    // a control sequence's parameters and final character
+    Control(String, char),
+    Escape(char),
+    // operating system commands such as window titles, which end in BEL or ST
+    OperatingSystem,
+    // cut off by the end of the input at this byte offset
+    Unfinished(usize),
+}
+
+// the escape sequence scanner shared by highlighted output and terminal recordings
+pub fn sequences(input: &str) -> impl Iterator<Item = Sequence> + '_ {
+    let mut chars = input.char_indices().peekable();
+    std::iter::from_fn(move || {
+        let (start, c) = chars.next()?;
+        if c != '\x1b' {
+            return Some(Sequence::Text(c));
+        }
+        Some(match chars.next() {
+            Some((_, '[')) => {
+                let mut parameters = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, c)) if ('\x40'..='\x7e').contains(&c) => {
+                            break Sequence::Control(parameters, c);
+                        }
+                        Some((_, c)) => parameters.push(c),
+                        None => break Sequence::Unfinished(start),
+                    }
+                }
+            }
+            Some((_, ']')) => loop {
+                match chars.next() {
+                    Some((_, '\x07')) => break Sequence::OperatingSystem,
+                    Some((_, '\x1b')) if chars.next_if(|(_, c)| *c == '\\').is_some() => {
+                        break Sequence::OperatingSystem;
+                    }
+                    Some(_) => {}
+                    None => break Sequence::Unfinished(start),
+                }
+            },
+            // character set designations carry one more character
+            Some((_, c @ ('(' | ')'))) => match chars.next() {
+                Some(_) => Sequence::Escape(c),
+                None => Sequence::Unfinished(start),
+            },
+            Some((_, c)) => Sequence::Escape(c),
+            None => Sequence::Unfinished(start),
+        })
+    })
+}
+
+fn parse_line(line: &str, style: &mut Style) -> Vec<(Style, String)> {
+    let mut segments: Vec<(Style, String)> = Vec::new();
+    for sequence in sequences(line) {
+        match sequence {
+            Sequence::Control(parameters, 'm') => style.apply(&parameters),
+            // a bare carriage return redraws the line, as progress bars do
+            Sequence::Text('\r') => segments.clear(),
+            Sequence::Text(c) if c.is_control() && c != '\t' => {}
+            Sequence::Text(c) => match segments.last_mut() {
+                Some((last, text)) if last == style => text.push(c),
+                _ => segments.push((*style, c.to_string())),
+            },
+            _ => {}
+        }
+    }
+    segments
+}
+
+fn render_segments(segments: &[(Style, String)], output: &mut String) {
+    for (style, text) in segments {
+        let classes = style.classes();
+        if classes.is_empty() {
+            output.push_str(&escape_html(text));
+        } else {
+            output.push_str(&format!(
+                r#"<span class="{classes}">{}</span>"#,
+                escape_html(text)
+            ));
+        }
+    }
+}
+
+fn split_segments(segments: Vec<(Style, String)>, at: usize) -> [Vec<(Style, String)>; 2] {
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    let mut position = 0;
+    for (style, text) in segments {
+        let end = position + text.len();
+        if end <= at {
+            before.push((style, text));
+        } else if position >= at {
+            after.push((style, text));
+        } else {
+            let (head, tail) = text.split_at(at - position);
+            before.push((style, head.to_string()));
+            after.push((style, tail.to_string()));
+        }
+        position = end;
+    }
+    [before, after]
+}
+
+// continued is set when a command ends in a backslash, so the next line is still command
+fn render_line(line: &str, style: &mut Style, continued: &mut bool) -> String {
+    let line = ESCAPE_NOTATION.replace_all(line, "\x1b[");
+    let (line, newline) = match line.strip_suffix('\n') {
+        Some(line) => (line.strip_suffix('\r').unwrap_or(line), "\n"),
+        None => (line.as_ref(), ""),
+    };
+    let segments = parse_line(line, style);
+    let plain = segments
+        .iter()
+        .map(|(_, text)| text.as_str())
+        .collect::<String>();
+    let mut output = String::new();
+    match PROMPT.find(&plain) {
+        _ if *continued => {
+            output.push_str(r#"<span class="ansi-command">"#);
+            render_segments(&segments, &mut output);
+            output.push_str("</span>");
+        }
+        Some(prompt) => {
+            let [prompt_segments, command] = split_segments(segments, prompt.end());
+            output.push_str(r#"<span class="ansi-prompt">"#);
+            render_segments(&prompt_segments, &mut output);
+            output.push_str(r#"</span><span class="ansi-command">"#);
+            render_segments(&command, &mut output);
+            output.push_str("</span>");
+        }
+        None => render_segments(&segments, &mut output),
+    }
+    *continued = (*continued || PROMPT.is_match(&plain)) && plain.trim_end().ends_with('\\');
+    output.push_str(newline);
+    output
+}
+
+// styles carry across lines the way a terminal would keep them
+pub fn render_ansi_lines(input: &str) -> Vec<String> {
+    let mut style = Style::default();
+    let mut continued = false;
+    LinesWithEndings::from(input)
+        .map(|line| render_line(line, &mut style, &mut continued))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classes(parameters: &str) -> String {
+        let mut style = Style::default();
+        style.apply(parameters);
+        style.classes()
+    }
+
+    #[test]
+    fn sgr_colours() {
+        assert_eq!(classes("31;42"), "ansi-fg-red ansi-bg-green");
+        assert_eq!(
+            classes("94;103"),
+            "ansi-fg-bright-blue ansi-bg-bright-yellow"
+        );
+        assert_eq!(classes("38;5;1"), "ansi-fg-red");
+        assert_eq!(classes("38;5;12"), "ansi-fg-bright-blue");
+        // cube and greyscale entries fold onto the nearest of the sixteen
+        assert_eq!(classes("38;5;196"), "ansi-fg-bright-red");
+        assert_eq!(classes("38;5;232"), "ansi-fg-black");
+        assert_eq!(classes("38;5;255"), "ansi-fg-white");
+        assert_eq!(classes("48;2;0;0;238"), "ansi-bg-blue");
+        assert_eq!(classes("38:2:250:250:250"), "ansi-fg-bright-white");
+        // the colour arguments are not read as codes of their own
+        assert_eq!(classes("38;5;1;4"), "ansi-underline ansi-fg-red");
+    }
+
+    #[test]
+    fn sgr_reset() {
+        assert_eq!(classes("1;2;3;31;44;0"), "");
+        assert_eq!(classes("1;31;"), "");
+        assert_eq!(classes("31;39;44;49"), "");
+        assert_eq!(classes("7;31;27"), "ansi-fg-red");
+    }
+
+    #[test]
+    fn bold_off() {
+        // 22 is normal intensity, so it ends dim as well as bold
+        assert_eq!(classes("1;2;22"), "");
+        assert_eq!(classes("1;3;22"), "ansi-italic");
+        assert_eq!(classes("7;31"), "ansi-inverse ansi-bg-red");
+    }
+
+    #[test]
+    fn styles_carry_across_lines() {
+        assert_eq!(
+            render_ansi_lines("\x1b[31ma\nb\x1b[0m\nc"),
+            [
+                "<span class=\"ansi-fg-red\">a</span>\n",
+                "<span class=\"ansi-fg-red\">b</span>\n",
+                "c"
+            ]
+        );
+        assert_eq!(
+            render_ansi_lines("\\e[1mbold\\e[22m\n"),
+            ["<span class=\"ansi-bold\">bold</span>\n"]
+        );
+    }
+
+    #[test]
+    fn prompts() {
+        let prompt = |line: &str, prompt: &str, command: &str| {
+            assert_eq!(
+                render_ansi_lines(line),
+                [format!(
+                    "<span class=\"ansi-prompt\">{prompt}</span><span class=\"ansi-command\">{command}</span>"
+                )]
+            );
+        };
+        prompt("$ ls -l", "$ ", "ls -l");
+        prompt("# reboot", "# ", "reboot");
+        prompt("user@host:~/src$ make", "user@host:~/src$ ", "make");
+        prompt("(venv) $ pip list", "(venv) $ ", "pip list");
+        prompt("PS C:\\Users> dir", "PS C:\\Users&gt; ", "dir");
+        for output in ["total 0", "50% done", "$5 off", "-rw-r--r-- 1 root"] {
+            assert_eq!(render_ansi_lines(output), [output]);
+        }
+    }
+
+    #[test]
+    fn prompt_continuation() {
+        assert_eq!(
+            render_ansi_lines("$ make \\\n  all \\\n  install\ndone\n"),
+            [
+                "<span class=\"ansi-prompt\">$ </span><span class=\"ansi-command\">make \\</span>\n",
+                "<span class=\"ansi-command\">  all \\</span>\n",
+                "<span class=\"ansi-command\">  install</span>\n",
+                "done\n",
+            ]
+        );
+        // output ending in a backslash does not make the next line a command
+        assert_eq!(render_ansi_lines("C:\\\nout\n"), ["C:\\\n", "out\n"]);
+    }
+}
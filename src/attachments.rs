@@ -0,0 +1,176 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Result;
+use sha2::{Digest, Sha256, Sha512};
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+
+use crate::{
+    content::SiteConfiguration,
+    util::{escape_html, human_size},
+};
+
+const SIGNATURE_EXTENSIONS: [&str; 2] = ["asc", "sig"];
+
+pub struct Attachment {
+    pub path: PathBuf,
+    pub size: usize,
+    pub sha256: String,
+    pub sha512: String,
+    pub signature: Option<PathBuf>,
+}
+
+fn with_extension_appended(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+fn is_signature(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| SIGNATURE_EXTENSIONS.contains(&extension))
+}
+
+pub fn scan_attachments(content_root: &Path) -> Result<Vec<Attachment>> {
+    let root = content_root.join("attachments");
+    if !root.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut attachments = Vec::new();
+    for entry in WalkDir::new(&root).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() || is_signature(entry.path()) {
+            continue;
+        }
+        let bytes = std::fs::read(entry.path())?;
+        let path = entry.path().strip_prefix(content_root)?.to_path_buf();
+        let signature = SIGNATURE_EXTENSIONS
+            .iter()
+            .map(|extension| with_extension_appended(&path, extension))
+            .find(|signature| content_root.join(signature).is_file());
+        attachments.push(Attachment {
+            path,
+            size: bytes.len(),
+            sha256: format!("{:x}", Sha256::digest(&bytes)),
+            sha512: format!("{:x}", Sha512::digest(&bytes)),
+            signature,
+        });
+    }
+    // a missing signature is only a mistake once the site signs its attachments at all
+    let signing = attachments
+        .iter()
+        .any(|attachment| attachment.signature.is_some());
+    for attachment in attachments
+        .iter()
+        .filter(|attachment| attachment.signature.is_none())
+    {
+        let path = attachment.path.to_string_lossy();
+        if signing {
+            warn!("attachment {path} has no detached .asc or .sig signature");
+        } else {
+            debug!("attachment {path} has no detached .asc or .sig signature");
+        }
+    }
+    Ok(attachments)
+}
+
+// the format sha256sum -c expects, run from inside the attachments directory
+pub fn sha256sums(attachments: &[Attachment]) -> String {
+    attachments
+        .iter()
+        .map(|attachment| {
+            let name = attachment
+                .path
+                .strip_prefix("attachments")
+                .unwrap_or(&attachment.path);
+            format!("{}  {}\n", attachment.sha256, name.to_string_lossy())
+        })
+        .collect()
+}
+
+pub fn render_checksum_table(config: &SiteConfiguration, attachments: &[&Attachment]) -> String {
+    let url = |path: &Path| escape_html(&config.resolve_relative_url(&path.to_string_lossy()));
+    let mut html = format!(
+        r#"<table class="checksums"><caption>Checksums, also listed in <a href="{}">SHA256SUMS</a></caption><thead><tr><th>File</th><th>Size</th><th>Digests</th><th>Signature</th></tr></thead><tbody>"#,
+        url(Path::new("attachments/SHA256SUMS"))
+    );
+    for attachment in attachments {
+        let name = attachment
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let signature = match &attachment.signature {
+            Some(signature) => format!(
+                r#"<a href="{}">{}</a>"#,
+                url(signature),
+                signature
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            ),
+            None => String::from("none"),
+        };
+        html.push_str(&format!(
+            r#"<tr><td><a href="{}">{}</a></td><td>{}</td><td>SHA-256 <code>{}</code><br />SHA-512 <code>{}</code></td><td>{signature}</td></tr>"#,
+            url(&attachment.path),
+            escape_html(&name),
+            human_size(attachment.size),
+            attachment.sha256,
+            attachment.sha512
+        ));
+    }
+    html.push_str("</tbody></table>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::content::test_configuration;
+
+    use super::*;
+
+    fn attachment(path: &str, size: usize, signature: Option<&str>) -> Attachment {
+        Attachment {
+            path: PathBuf::from(path),
+            size,
+            sha256: "a".repeat(64),
+            sha512: "b".repeat(128),
+            signature: signature.map(PathBuf::from),
+        }
+    }
+
+    #[test]
+    fn sha256sums_are_relative_to_the_attachments() {
+        let attachments = [
+            attachment("attachments/tool.py", 10, None),
+            attachment("attachments/firmware/image.bin", 10, None),
+        ];
+        assert_eq!(
+            sha256sums(&attachments),
+            format!("{0}  tool.py\n{0}  firmware/image.bin\n", "a".repeat(64))
+        );
+        assert_eq!(sha256sums(&[]), "");
+    }
+
+    #[test]
+    fn checksum_table() {
+        let config = test_configuration();
+        let signed = attachment("attachments/a&b.py", 2048, Some("attachments/a&b.py.asc"));
+        let unsigned = attachment("attachments/notes.txt", 12, None);
+        let html = render_checksum_table(&config, &[&signed, &unsigned]);
+        assert!(
+            html.contains(r#"<a href="https://example.com/attachments/SHA256SUMS">SHA256SUMS</a>"#)
+        );
+        assert!(html.contains(&format!(
+            r#"<tr><td><a href="https://example.com/attachments/a&amp;b.py">a&amp;b.py</a></td><td>2.0 KiB (2048 bytes)</td><td>SHA-256 <code>{}</code><br />SHA-512 <code>{}</code></td><td><a href="https://example.com/attachments/a&amp;b.py.asc">asc</a></td></tr>"#,
+            "a".repeat(64),
+            "b".repeat(128)
+        )));
+        assert!(html.contains("<td>12 bytes</td>"));
+        assert!(html.contains("<td>none</td></tr>"));
+        assert!(html.ends_with("</tbody></table>"));
+    }
+}
@@ -0,0 +1,370 @@
+use color_eyre::eyre::{Result, bail};
+use xmltree::XMLNode;
+
+use crate::{
+    content::SiteConfiguration,
+    diagrams::{Diagnostics, DiagramLabels, element, text, text_width, themed_style},
+    shortcodes::Arguments,
+};
+
+const BIT_CELL: f64 = 18.0;
+const BYTE_CELL: f64 = 40.0;
+const ROW: f64 = 36.0;
+const HEADER: f64 = 20.0;
+const MARGIN: f64 = 10.0;
+const PADDING: f64 = 4.0;
+const FONT_SIZE: f64 = 13.0;
+const OFFSET_FONT_SIZE: f64 = 11.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Unit {
+    Bit,
+    Byte,
+}
+
+impl Unit {
+    fn describe(self, count: u64) -> String {
+        match (self, count) {
+            (Self::Bit, 1) => String::from("1 bit"),
+            (Self::Bit, count) => format!("{count} bits"),
+            (Self::Byte, 1) => String::from("1 byte"),
+            (Self::Byte, count) => format!("{count} bytes"),
+        }
+    }
+}
+
+pub struct ByteFieldOptions {
+    unit: Unit,
+    row: u64,
+    labels: DiagramLabels,
+}
+
+impl ByteFieldOptions {
+    pub fn from_arguments(args: &mut Arguments) -> Result<Self> {
+        let unit = match args.optional("unit").as_deref() {
+            None | Some("bit" | "bits") => Unit::Bit,
+            Some("byte" | "bytes") => Unit::Byte,
+            Some(unit) => bail!("{unit} is not a field unit; expected bit or byte"),
+        };
+        let row = args.parsed(
+            "row",
+            match unit {
+                Unit::Bit => 32,
+                Unit::Byte => 16,
+            },
+        )?;
+        if row == 0 || row > 64 {
+            bail!("row must be between 1 and 64 units wide");
+        }
+        if unit == Unit::Bit && row % 8 != 0 {
+            bail!("row must be a whole number of bytes");
+        }
+        Ok(Self {
+            unit,
+            row,
+            labels: DiagramLabels::from_arguments(args),
+        })
+    }
+
+    pub fn default_title(&mut self, title: &str) {
+        self.labels.default_title(title);
+    }
+}
+
+enum Width {
+    Fixed(u64),
+    Variable,
+}
+
+struct Field {
+    label: String,
+    width: Width,
+    offset: Option<u64>,
+}
+
+struct Segment {
+    field: usize,
+    row: u64,
+    column: u64,
+    width: u64,
+}
+
+fn parse_fields(source: &str) -> Result<Vec<Field>> {
+    let mut fields = Vec::new();
+    let mut offset = Some(0);
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: String| Diagnostics::single(index + 1, 1, message);
+        let (width, label) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let width = match width {
+            "*" => Width::Variable,
+            width => match width.parse() {
+                Ok(0) | Err(_) => {
+                    return Err(error(format!(
+                        "expected a positive field width or *, found {width}"
+                    ))
+                    .into());
+                }
+                Ok(width) => Width::Fixed(width),
+            },
+        };
+        let label = label.trim();
+        if label.is_empty() {
+            return Err(error(String::from("field needs a label")).into());
+        }
+        fields.push(Field {
+            label: label.to_string(),
+            offset,
+            width,
+        });
+        offset = match (offset, &fields.last().expect("just pushed").width) {
+            (Some(offset), Width::Fixed(width)) => Some(offset + width),
+            _ => None,
+        };
+    }
+    if fields.is_empty() {
+        bail!("a byte field diagram needs at least one field");
+    }
+    Ok(fields)
+}
+
+fn layout(fields: &[Field], row_width: u64) -> (Vec<Segment>, Vec<Option<u64>>) {
+    let mut segments = Vec::new();
+    let mut row_offsets = Vec::new();
+    let (mut row, mut column) = (0, 0);
+    for (index, field) in fields.iter().enumerate() {
+        let mut remaining = match field.width {
+            Width::Fixed(width) => width,
+            Width::Variable => row_width - column,
+        };
+        let mut consumed = 0;
+        while remaining > 0 {
+            if column == 0 {
+                row_offsets.push(field.offset.map(|offset| offset + consumed));
+            }
+            let width = remaining.min(row_width - column);
+            segments.push(Segment {
+                field: index,
+                row,
+                column,
+                width,
+            });
+            (remaining, consumed, column) = (remaining - width, consumed + width, column + width);
+            if column == row_width {
+                (row, column) = (row + 1, 0);
+            }
+        }
+    }
+    (segments, row_offsets)
+}
+
+fn outline(fields: &[Field], unit: Unit) -> String {
+    let fields = fields
+        .iter()
+        .map(|field| {
+            let size = match field.width {
+                Width::Fixed(width) => unit.describe(width),
+                Width::Variable => String::from("variable length"),
+            };
+            match (field.offset, unit) {
+                (Some(offset), Unit::Bit) => format!("{} ({size} at bit {offset})", field.label),
+                (Some(offset), Unit::Byte) => format!("{} ({size} at byte {offset})", field.label),
+                (None, _) => format!("{} ({size})", field.label),
+            }
+        })
+        .collect::<Vec<_>>();
+    format!("Fields: {}.", fields.join("; "))
+}
+
+pub fn compile_bytefield(
+    config: &SiteConfiguration,
+    source: &str,
+    options: &ByteFieldOptions,
+) -> Result<String> {
+    let fields = parse_fields(source)?;
+    let (segments, row_offsets) = layout(&fields, options.row);
+    let cell = match options.unit {
+        Unit::Bit => BIT_CELL,
+        Unit::Byte => BYTE_CELL,
+    };
+    let bytes = |offset: u64| match options.unit {
+        Unit::Bit => offset / 8,
+        Unit::Byte => offset,
+    };
+    let digits = row_offsets
+        .iter()
+        .flatten()
+        .map(|offset| format!("{:x}", bytes(*offset)).len())
+        .fold(2, usize::max);
+    let left = MARGIN + text_width(&format!("0x{:0digits$x}", 0), OFFSET_FONT_SIZE) + MARGIN;
+    let width = left + options.row as f64 * cell + MARGIN;
+    let top = MARGIN + HEADER;
+    let height = top + row_offsets.len() as f64 * ROW + MARGIN;
+
+    let mut root = element(
+        "svg",
+        &[
+            ("xmlns", String::from("http://www.w3.org/2000/svg")),
+            ("width", width.to_string()),
+            ("height", height.to_string()),
+            ("viewBox", format!("0 0 {width} {height}")),
+        ],
+    );
+    // wide rows only number every byte boundary so the header stays legible
+    let step = if options.unit == Unit::Bit && options.row > 32 {
+        8
+    } else {
+        1
+    };
+    for column in (0..options.row).step_by(step) {
+        let label = match options.unit {
+            Unit::Bit => column.to_string(),
+            Unit::Byte => format!("{column:x}"),
+        };
+        root.children.push(XMLNode::Element(text(
+            left + (column as f64 + 0.5) * cell,
+            MARGIN + HEADER / 2.0,
+            "offset",
+            "middle",
+            &label,
+        )));
+    }
+    for (row, offset) in row_offsets.iter().enumerate() {
+        if let Some(offset) = offset {
+            root.children.push(XMLNode::Element(text(
+                left - MARGIN,
+                top + (row as f64 + 0.5) * ROW,
+                "offset",
+                "end",
+                &format!("0x{:0digits$x}", bytes(*offset)),
+            )));
+        }
+    }
+    for (index, field) in fields.iter().enumerate() {
+        let pieces = segments
+            .iter()
+            .filter(|segment| segment.field == index)
+            .collect::<Vec<_>>();
+        let widest = pieces
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, segment)| segment.width)
+            .map(|(position, _)| position);
+        let class = match field.width {
+            Width::Fixed(_) => "field",
+            Width::Variable => "field variable",
+        };
+        for (position, segment) in pieces.iter().enumerate() {
+            let x = left + segment.column as f64 * cell;
+            let y = top + segment.row as f64 * ROW;
+            let span = segment.width as f64 * cell;
+            root.children.push(XMLNode::Element(element(
+                "rect",
+                &[
+                    ("class", class.to_string()),
+                    ("x", x.to_string()),
+                    ("y", y.to_string()),
+                    ("width", span.to_string()),
+                    ("height", ROW.to_string()),
+                ],
+            )));
+            let label = if Some(position) == widest {
+                field.label.as_str()
+            } else {
+                "…"
+            };
+            let mut label = text(x + span / 2.0, y + ROW / 2.0, "label", "middle", label);
+            let available = span - 2.0 * PADDING;
+            if text_width(&field.label, FONT_SIZE) > available && Some(position) == widest {
+                label
+                    .attributes
+                    .insert(String::from("textLength"), available.max(1.0).to_string());
+                label.attributes.insert(
+                    String::from("lengthAdjust"),
+                    String::from("spacingAndGlyphs"),
+                );
+            }
+            root.children.push(XMLNode::Element(label));
+        }
+    }
+
+    let family = config.get_fontbook().regular_family()?;
+    root.children.push(XMLNode::Element(themed_style(|palette| {
+        format!(
+            "text{{font-family:\"{family}\",sans-serif;font-size:{FONT_SIZE}px;fill:{}}}.offset{{font-size:{OFFSET_FONT_SIZE}px}}\
+             .field{{fill:{};stroke:{};stroke-width:1.5}}.variable{{stroke-dasharray:4 3}}",
+            palette.text, palette.fill, palette.stroke
+        )
+    })));
+    options
+        .labels
+        .apply(&mut root, |_| Some(outline(&fields, options.unit)));
+    let mut destination = Vec::new();
+    root.write(&mut destination)?;
+    Ok(String::from_utf8(destination)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placed(segments: &[Segment]) -> Vec<(usize, u64, u64, u64)> {
+        segments
+            .iter()
+            .map(|segment| (segment.field, segment.row, segment.column, segment.width))
+            .collect()
+    }
+
+    #[test]
+    fn fields_share_a_row() -> Result<()> {
+        let fields = parse_fields("# header\n8 type\n\n16 length\n* payload\n")?;
+        let (segments, row_offsets) = layout(&fields, 32);
+        assert_eq!(
+            placed(&segments),
+            [(0, 0, 0, 8), (1, 0, 8, 16), (2, 0, 24, 8)]
+        );
+        assert_eq!(row_offsets, [Some(0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn wide_fields_wrap() -> Result<()> {
+        let fields = parse_fields("48 source\n48 destination\n16 ethertype\n")?;
+        let (segments, row_offsets) = layout(&fields, 32);
+        assert_eq!(
+            placed(&segments),
+            [
+                (0, 0, 0, 32),
+                (0, 1, 0, 16),
+                (1, 1, 16, 16),
+                (1, 2, 0, 32),
+                (2, 3, 0, 16)
+            ]
+        );
+        assert_eq!(row_offsets, [Some(0), Some(32), Some(64), Some(96)]);
+        Ok(())
+    }
+
+    #[test]
+    fn offsets_are_unknown_after_a_variable_field() -> Result<()> {
+        let fields = parse_fields("4 kind\n* options\n16 checksum\n")?;
+        let (segments, row_offsets) = layout(&fields, 8);
+        assert_eq!(
+            placed(&segments),
+            [(0, 0, 0, 4), (1, 0, 4, 4), (2, 1, 0, 8), (2, 2, 0, 8)]
+        );
+        assert_eq!(row_offsets, [Some(0), None, None]);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_fields() {
+        assert!(parse_fields("").is_err());
+        assert!(parse_fields("0 empty").is_err());
+        assert!(parse_fields("x label").is_err());
+        assert!(parse_fields("8").is_err());
+    }
+}
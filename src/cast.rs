@@ -0,0 +1,670 @@
+use std::path::Path;
+
+use color_eyre::eyre::{Result, bail, eyre};
+use serde_json::Value;
+use xmltree::{Element, XMLNode};
+
+use crate::{
+    ansi::{Sequence, Style, sequences, svg_css},
+    content::{ContentReference, SiteConfiguration},
+    diagrams::{Diagnostics, DiagramLabels, element},
+    palette::{Scheme, resolve},
+};
+
+const CELL_WIDTH: f64 = 8.4;
+const LINE_HEIGHT: f64 = 18.0;
+const FONT_SIZE: f64 = 14.0;
+const PADDING: f64 = 12.0;
+// output arriving within one display frame of the previous is drawn together
+const FRAME_INTERVAL: f64 = 1.0 / 30.0;
+const FINAL_HOLD: f64 = 2.0;
+
+fn columns(count: usize) -> String {
+    // rounded so that multiples of the cell width don't print float noise
+    ((count as f64 * CELL_WIDTH * 100.0).round() / 100.0).to_string()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Cell {
+    character: char,
+    style: Style,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            character: ' ',
+            style: Style::default(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct Screen {
+    cells: Vec<Vec<Cell>>,
+    cursor: Option<(usize, usize)>,
+}
+
+struct Terminal {
+    width: usize,
+    height: usize,
+    cells: Vec<Vec<Cell>>,
+    alternate: Option<Vec<Vec<Cell>>>,
+    row: usize,
+    column: usize,
+    saved: (usize, usize),
+    style: Style,
+    cursor_visible: bool,
+    pending: String,
+}
+
+impl Terminal {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![vec![Cell::default(); width]; height],
+            alternate: None,
+            row: 0,
+            column: 0,
+            saved: (0, 0),
+            style: Style::default(),
+            cursor_visible: true,
+            pending: String::new(),
+        }
+    }
+
+    fn screen(&self) -> Screen {
+        Screen {
+            cells: self.cells.clone(),
+            cursor: self
+                .cursor_visible
+                .then_some((self.row, self.column.min(self.width - 1))),
+        }
+    }
+
+    fn line_feed(&mut self) {
+        if self.row + 1 == self.height {
+            self.cells.remove(0);
+            self.cells.push(vec![Cell::default(); self.width]);
+        } else {
+            self.row += 1;
+        }
+    }
+
+    fn put(&mut self, character: char) {
+        // the cursor parks past the last column until the next character wraps it
+        if self.column >= self.width {
+            self.column = 0;
+            self.line_feed();
+        }
+        self.cells[self.row][self.column] = Cell {
+            character,
+            style: self.style,
+        };
+        self.column += 1;
+    }
+
+    fn erase(&mut self, row: usize, columns: std::ops::Range<usize>) {
+        let end = columns.end.min(self.width);
+        for cell in &mut self.cells[row][columns.start.min(end)..end] {
+            *cell = Cell::default();
+        }
+    }
+
+    fn csi(&mut self, parameters: &str, command: char) {
+        if let Some(private) = parameters.strip_prefix('?') {
+            let enable = command == 'h';
+            for mode in private.split(';') {
+                match mode {
+                    "25" => self.cursor_visible = enable,
+                    "47" | "1047" | "1049" if enable && self.alternate.is_none() => {
+                        let blank = vec![vec![Cell::default(); self.width]; self.height];
+                        self.alternate = Some(std::mem::replace(&mut self.cells, blank));
+                    }
+                    "47" | "1047" | "1049" if !enable => {
+                        if let Some(main) = self.alternate.take() {
+                            self.cells = main;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            return;
+        }
+        let numbers = parameters
+            .split(';')
+            .map(|number| number.parse::<usize>().unwrap_or(0))
+            .collect::<Vec<_>>();
+        let argument = |index: usize| numbers.get(index).copied().unwrap_or(0);
+        let count = argument(0).max(1);
+        let last_row = self.height - 1;
+        let last_column = self.width - 1;
+        match command {
+            'm' => self.style.apply(parameters),
+            'A' => self.row = self.row.saturating_sub(count),
+            'B' => self.row = (self.row + count).min(last_row),
+            'C' => self.column = (self.column + count).min(last_column),
+            'D' => self.column = self.column.min(last_column).saturating_sub(count),
+            'E' => (self.row, self.column) = ((self.row + count).min(last_row), 0),
+            'F' => (self.row, self.column) = (self.row.saturating_sub(count), 0),
+            'G' => self.column = (count - 1).min(last_column),
+            'd' => self.row = (count - 1).min(last_row),
+            'H' | 'f' => {
+                self.row = (argument(0).max(1) - 1).min(last_row);
+                self.column = (argument(1).max(1) - 1).min(last_column);
+            }
+            'J' => {
+                let (row, column) = (self.row, self.column);
+                match argument(0) {
+                    0 => {
+                        self.erase(row, column..self.width);
+                        for row in row + 1..self.height {
+                            self.erase(row, 0..self.width);
+                        }
+                    }
+                    1 => {
+                        for row in 0..row {
+                            self.erase(row, 0..self.width);
+                        }
+                        self.erase(row, 0..column + 1);
+                    }
+                    _ => {
+                        for row in 0..self.height {
+                            self.erase(row, 0..self.width);
+                        }
+                    }
+                }
+            }
+            'K' => {
+                let (row, column) = (self.row, self.column);
+                match argument(0) {
+                    0 => self.erase(row, column..self.width),
+                    1 => self.erase(row, 0..column + 1),
+                    _ => self.erase(row, 0..self.width),
+                }
+            }
+            'X' => {
+                let (row, column) = (self.row, self.column);
+                self.erase(row, column..column + count);
+            }
+            'P' | '@' => {
+                let column = self.column.min(last_column);
+                let line = &mut self.cells[self.row];
+                for _ in 0..count.min(self.width - column) {
+                    if command == 'P' {
+                        line.remove(column);
+                        line.push(Cell::default());
+                    } else {
+                        line.insert(column, Cell::default());
+                        line.pop();
+                    }
+                }
+            }
+            'L' | 'M' => {
+                for _ in 0..count.min(self.height - self.row) {
+                    if command == 'L' {
+                        self.cells
+                            .insert(self.row, vec![Cell::default(); self.width]);
+                        self.cells.pop();
+                    } else {
+                        self.cells.remove(self.row);
+                        self.cells.push(vec![Cell::default(); self.width]);
+                    }
+                }
+            }
+            'S' => {
+                for _ in 0..count.min(self.height) {
+                    self.cells.remove(0);
+                    self.cells.push(vec![Cell::default(); self.width]);
+                }
+            }
+            'T' => {
+                for _ in 0..count.min(self.height) {
+                    self.cells.pop();
+                    self.cells.insert(0, vec![Cell::default(); self.width]);
+                }
+            }
+            's' => self.saved = (self.row, self.column),
+            'u' => (self.row, self.column) = self.saved,
+            _ => {}
+        }
+    }
+
+    fn feed(&mut self, data: &str) {
+        let input = std::mem::take(&mut self.pending) + data;
+        for sequence in sequences(&input) {
+            match sequence {
+                Sequence::Control(parameters, command) => self.csi(&parameters, command),
+                Sequence::Escape('7') => self.saved = (self.row, self.column),
+                Sequence::Escape('8') => (self.row, self.column) = self.saved,
+                Sequence::Escape('M') => {
+                    if self.row == 0 {
+                        self.cells.pop();
+                        self.cells.insert(0, vec![Cell::default(); self.width]);
+                    } else {
+                        self.row -= 1;
+                    }
+                }
+                Sequence::Escape('c') => *self = Self::new(self.width, self.height),
+                Sequence::Escape(_) | Sequence::OperatingSystem => {}
+                // sequences split across events are finished by the next event
+                Sequence::Unfinished(start) => {
+                    self.pending = input[start..].to_string();
+                    return;
+                }
+                Sequence::Text('\n' | '\x0b' | '\x0c') => self.line_feed(),
+                Sequence::Text('\r') => self.column = 0,
+                Sequence::Text('\x08') => {
+                    self.column = self.column.min(self.width - 1).saturating_sub(1)
+                }
+                Sequence::Text('\t') => {
+                    self.column = ((self.column / 8 + 1) * 8).min(self.width - 1)
+                }
+                Sequence::Text(character) if character.is_control() => {}
+                Sequence::Text(character) => self.put(character),
+            }
+        }
+    }
+}
+
+pub struct Cast {
+    width: usize,
+    height: usize,
+    frames: Vec<(f64, Screen)>,
+    duration: f64,
+    labels: DiagramLabels,
+}
+
+impl Cast {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut lines = source.lines().enumerate();
+        let (_, header) = lines.next().ok_or_else(|| eyre!("recording is empty"))?;
+        let header: Value = serde_json::from_str(header)
+            .map_err(|err| Diagnostics::single(1, err.column(), err.to_string()))?;
+        if header.get("version").and_then(Value::as_u64) != Some(2) {
+            bail!("only asciinema v2 recordings are supported");
+        }
+        let dimension = |key: &str| {
+            header
+                .get(key)
+                .and_then(Value::as_u64)
+                .and_then(|value| usize::try_from(value).ok())
+                .filter(|value| (1..=500).contains(value))
+                .ok_or_else(|| eyre!("header needs a {key} between 1 and 500"))
+        };
+        let (width, height) = (dimension("width")?, dimension("height")?);
+        let idle_limit = header
+            .get("idle_time_limit")
+            .and_then(Value::as_f64)
+            .unwrap_or(f64::INFINITY);
+        let title = header
+            .get("title")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        let mut terminal = Terminal::new(width, height);
+        let mut frames: Vec<(f64, Screen)> = vec![(0.0, terminal.screen())];
+        let (mut clock, mut previous) = (0.0, 0.0);
+        for (index, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let error = |message: String| Diagnostics::single(index + 1, 1, message);
+            let event: (f64, String, String) = serde_json::from_str(line)
+                .map_err(|err| Diagnostics::single(index + 1, err.column(), err.to_string()))?;
+            let (time, code, data) = event;
+            if !time.is_finite() || time < previous {
+                return Err(error(format!("event time {time} goes backwards")).into());
+            }
+            clock += (time - previous).min(idle_limit);
+            previous = time;
+            if code != "o" {
+                continue;
+            }
+            terminal.feed(&data);
+            let screen = terminal.screen();
+            match frames.last_mut() {
+                Some((_, last)) if *last == screen => {}
+                Some((start, last)) if clock - *start < FRAME_INTERVAL => *last = screen,
+                _ => frames.push((clock, screen)),
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            duration: clock + FINAL_HOLD,
+            frames,
+            labels: DiagramLabels::new(title, None),
+        })
+    }
+
+    // asciinema headers have no room for options, so a recording.poster beside the recording asks for
+    // a still. it holds the time in seconds to take it at, or nothing for the final screen
+    pub fn poster_time(
+        config: &SiteConfiguration,
+        content_reference: &ContentReference,
+    ) -> Result<Option<f64>> {
+        let sidecar = content_reference
+            .get_source_path(config)
+            .with_extension("poster");
+        if !sidecar.is_file() {
+            return Ok(None);
+        }
+        let time = std::fs::read_to_string(&sidecar)?;
+        match time.trim() {
+            "" => Ok(Some(f64::INFINITY)),
+            time => time
+                .parse::<f64>()
+                .ok()
+                .filter(|time| time.is_finite() && *time >= 0.0)
+                .map(Some)
+                .ok_or_else(|| {
+                    eyre!(
+                        "{} must be empty or a time in seconds, not {time}",
+                        sidecar.to_string_lossy()
+                    )
+                }),
+        }
+    }
+
+    fn screen_size(&self) -> (f64, f64) {
+        (
+            (self.width as f64 * CELL_WIDTH * 100.0).round() / 100.0,
+            self.height as f64 * LINE_HEIGHT,
+        )
+    }
+
+    fn draw_screen(&self, screen: &Screen, top: f64) -> Element {
+        let mut group = element("g", &[]);
+        if top != 0.0 {
+            group
+                .attributes
+                .insert(String::from("transform"), format!("translate(0 {top})"));
+        }
+        for (row, cells) in screen.cells.iter().enumerate() {
+            let y = row as f64 * LINE_HEIGHT;
+            let mut column = 0;
+            for run in
+                cells.chunk_by(|a, b| a.style.background_class() == b.style.background_class())
+            {
+                if let Some(class) = run[0].style.background_class() {
+                    group.children.push(XMLNode::Element(element(
+                        "rect",
+                        &[
+                            ("class", class),
+                            ("x", columns(column)),
+                            ("y", y.to_string()),
+                            ("width", columns(run.len())),
+                            ("height", LINE_HEIGHT.to_string()),
+                        ],
+                    )));
+                }
+                column += run.len();
+            }
+            if let Some((cursor_row, cursor_column)) = screen.cursor
+                && cursor_row == row
+            {
+                group.children.push(XMLNode::Element(element(
+                    "rect",
+                    &[
+                        ("class", String::from("cursor")),
+                        ("x", columns(cursor_column)),
+                        ("y", y.to_string()),
+                        ("width", CELL_WIDTH.to_string()),
+                        ("height", LINE_HEIGHT.to_string()),
+                    ],
+                )));
+            }
+            let used = cells
+                .iter()
+                .rposition(|cell| cell.character != ' ')
+                .map_or(0, |last| last + 1);
+            if used == 0 {
+                continue;
+            }
+            let mut line = element(
+                "text",
+                &[
+                    ("y", (y + LINE_HEIGHT * 0.75).to_string()),
+                    ("xml:space", String::from("preserve")),
+                ],
+            );
+            let mut column = 0;
+            for run in cells[..used].chunk_by(|a, b| a.style == b.style) {
+                let text = run.iter().map(|cell| cell.character).collect::<String>();
+                let mut span = element("tspan", &[("x", columns(column))]);
+                let classes = run[0].style.classes();
+                if !classes.is_empty() {
+                    span.attributes.insert(String::from("class"), classes);
+                }
+                span.children.push(XMLNode::Text(text));
+                line.children.push(XMLNode::Element(span));
+                column += run.len();
+            }
+            group.children.push(XMLNode::Element(line));
+        }
+        group
+    }
+
+    fn render_with<F>(&self, config: &SiteConfiguration, draw: F, animation: &str) -> Result<String>
+    where
+        F: FnOnce(&Self) -> Element,
+    {
+        let (screen_width, screen_height) = self.screen_size();
+        let (width, height) = (screen_width + 2.0 * PADDING, screen_height + 2.0 * PADDING);
+        let mut root = element(
+            "svg",
+            &[
+                ("xmlns", String::from("http://www.w3.org/2000/svg")),
+                ("width", width.to_string()),
+                ("height", height.to_string()),
+                ("viewBox", format!("0 0 {width} {height}")),
+            ],
+        );
+        root.children.push(XMLNode::Element(element(
+            "rect",
+            &[
+                ("class", String::from("background")),
+                ("width", width.to_string()),
+                ("height", height.to_string()),
+                ("rx", String::from("4")),
+            ],
+        )));
+        let mut viewport = element(
+            "svg",
+            &[
+                ("x", PADDING.to_string()),
+                ("y", PADDING.to_string()),
+                ("width", screen_width.to_string()),
+                ("height", screen_height.to_string()),
+                ("viewBox", format!("0 0 {screen_width} {screen_height}")),
+            ],
+        );
+        viewport.children.push(XMLNode::Element(draw(self)));
+        root.children.push(XMLNode::Element(viewport));
+
+        let family = config.get_fontbook().monospace_family()?;
+        // recordings are drawn in the dark scheme's code box colours
+        let foreground = resolve("var(--textcolor)", Scheme::Dark)?;
+        let background = resolve("var(--box-fill)", Scheme::Dark)?;
+        let mut style = element("style", &[]);
+        style.children.push(XMLNode::Text(format!(
+            "text{{font-family:\"{family}\",monospace;font-size:{FONT_SIZE}px;fill:{foreground}}}\
+             .background{{fill:{background}}}.cursor{{fill:{foreground};opacity:0.6}}{}{animation}",
+            svg_css()
+        )));
+        root.children.push(XMLNode::Element(style));
+        self.labels.apply(&mut root, |_| {
+            Some(format!(
+                "Terminal recording lasting {:.0} seconds.",
+                self.duration - FINAL_HOLD
+            ))
+        });
+        let mut destination = Vec::new();
+        root.write(&mut destination)?;
+        Ok(String::from_utf8(destination)?)
+    }
+
+    pub fn render(&self, config: &SiteConfiguration) -> Result<String> {
+        let (_, screen_height) = self.screen_size();
+        let last = self.frames.len() - 1;
+        // frames are stacked into a strip that steps upwards past the viewport
+        let mut keyframes = String::new();
+        for (index, (time, _)) in self.frames.iter().enumerate() {
+            keyframes.push_str(&format!(
+                "{:.3}%{{transform:translateY(-{}px)}}",
+                time / self.duration * 100.0,
+                index as f64 * screen_height
+            ));
+        }
+        let animation = format!(
+            ".film{{animation:play {:.3}s steps(1,end) infinite}}@keyframes play{{{keyframes}}}\
+             @media (prefers-reduced-motion:reduce){{.film{{animation:none;transform:translateY(-{}px)}}}}",
+            self.duration,
+            last as f64 * screen_height
+        );
+        self.render_with(
+            config,
+            |cast| {
+                let mut film = element("g", &[("class", String::from("film"))]);
+                for (index, (_, screen)) in cast.frames.iter().enumerate() {
+                    film.children.push(XMLNode::Element(
+                        cast.draw_screen(screen, index as f64 * screen_height),
+                    ));
+                }
+                film
+            },
+            &animation,
+        )
+    }
+
+    fn frame_at(&self, time: f64) -> &Screen {
+        let (_, screen) = self
+            .frames
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= time)
+            .expect("frames start with a blank screen at zero");
+        screen
+    }
+
+    pub fn render_poster(&self, config: &SiteConfiguration, time: f64) -> Result<String> {
+        self.render_with(
+            config,
+            |cast| cast.draw_screen(cast.frame_at(time), 0.0),
+            "",
+        )
+    }
+
+    pub fn poster_path(output: &str) -> Option<String> {
+        output
+            .strip_suffix(".svg")
+            .map(|stem| format!("{stem}.poster.svg"))
+    }
+
+    pub fn find_poster(
+        config: &SiteConfiguration,
+        content_reference: &ContentReference,
+        src: &str,
+    ) -> Option<String> {
+        let poster = Self::poster_path(src)?;
+        let recording = Path::new(src).with_extension("cast");
+        (content_reference
+            .resolve_relative_content_path(config, &recording)
+            .is_file()
+            && content_reference
+                .resolve_relative_path(config, &poster)
+                .is_file())
+        .then_some(poster)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(terminal: &Terminal) -> Vec<String> {
+        terminal
+            .cells
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| cell.character)
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cursor_movement() {
+        let mut terminal = Terminal::new(10, 4);
+        terminal.feed("abc\x1b[2;5Hx\x1b[Ay\x1b[3Cz\x1b[10D!\x1b[4;1H\x1b[2Bq");
+        assert_eq!(rows(&terminal), ["!bc  y   z", "    x", "", "q"]);
+        terminal.feed("\x1b7\x1b[1;1H\x1b8r");
+        assert_eq!(rows(&terminal)[3], "qr");
+    }
+
+    #[test]
+    fn erase() {
+        let mut terminal = Terminal::new(6, 3);
+        terminal.feed("abcdef\r\n123456\r\nuvwxyz\x1b[1;3H\x1b[K\x1b[2;4H\x1b[1K\x1b[3;2H\x1b[2X");
+        assert_eq!(rows(&terminal), ["ab", "    56", "u  xyz"]);
+        terminal.feed("\x1b[2;2H\x1b[J");
+        assert_eq!(rows(&terminal), ["ab", "", ""]);
+        terminal.feed("\x1b[2J");
+        assert_eq!(rows(&terminal), ["", "", ""]);
+    }
+
+    #[test]
+    fn wrap_and_scroll() {
+        let mut terminal = Terminal::new(4, 2);
+        terminal.feed("abcd");
+        // the cursor waits past the last column rather than wrapping early
+        assert_eq!(rows(&terminal), ["abcd", ""]);
+        assert_eq!(terminal.screen().cursor, Some((0, 3)));
+        terminal.feed("efghij");
+        assert_eq!(rows(&terminal), ["efgh", "ij"]);
+        assert_eq!(terminal.screen().cursor, Some((1, 2)));
+    }
+
+    #[test]
+    fn sgr_reset() {
+        let mut terminal = Terminal::new(4, 1);
+        terminal.feed("\x1b[1;31ma\x1b[0mb\x1b[32mc\x1b[39md");
+        let classes = terminal.cells[0]
+            .iter()
+            .map(|cell| cell.style.classes())
+            .collect::<Vec<_>>();
+        assert_eq!(classes, ["ansi-bold ansi-fg-red", "", "ansi-fg-green", ""]);
+    }
+
+    #[test]
+    fn sequences_split_across_events() {
+        let mut terminal = Terminal::new(4, 1);
+        terminal.feed("\x1b[3");
+        terminal.feed("1ma\x1b]0;ti");
+        terminal.feed("tle\x07b");
+        assert_eq!(rows(&terminal), ["ab"]);
+        assert_eq!(terminal.cells[0][1].style.classes(), "ansi-fg-red");
+    }
+
+    #[test]
+    fn poster_frame() -> Result<()> {
+        let cast = Cast::parse(
+            "{\"version\": 2, \"width\": 4, \"height\": 1}\n[1.0, \"o\", \"a\"]\n[2.0, \"o\", \"b\"]\n",
+        )?;
+        let frame = |time: f64| {
+            cast.frame_at(time).cells[0][..2]
+                .iter()
+                .map(|cell| cell.character)
+                .collect::<String>()
+        };
+        assert_eq!(frame(0.5), "  ");
+        assert_eq!(frame(1.5), "a ");
+        assert_eq!(frame(f64::INFINITY), "ab");
+        Ok(())
+    }
+}
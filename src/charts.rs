@@ -0,0 +1,626 @@
+use std::{collections::HashMap, ops::Range, path::Path};
+
+use color_eyre::eyre::{OptionExt, Result, WrapErr, bail, eyre};
+use plotters::{
+    coord::{
+        Shift,
+        ranged1d::{AsRangedCoord, ValueFormatter},
+    },
+    prelude::*,
+};
+use serde::Deserialize;
+use xmltree::{Element, XMLNode};
+
+use crate::{
+    content::{ContentReference, SiteConfiguration},
+    diagrams::{DiagramLabels, themed_style},
+};
+
+// plotters writes colours as literal attributes, so each role is drawn in a placeholder colour and
+// swapped for a class afterwards so that the palette can follow prefers-color-scheme; they also
+// carry an opacity nothing else is drawn with, so only both together mark a placeholder
+const MARKER: f64 = 0.123;
+const TEXT: RGBAColor = RGBAColor(0, 0, 1, MARKER);
+const AXIS: RGBAColor = RGBAColor(0, 0, 2, MARKER);
+const GRID: RGBAColor = RGBAColor(0, 0, 3, MARKER);
+const BACKGROUND: RGBAColor = RGBAColor(0, 0, 4, MARKER);
+const SERIES: [RGBAColor; 3] = [
+    RGBAColor(0, 0, 5, MARKER),
+    RGBAColor(0, 0, 6, MARKER),
+    RGBAColor(0, 0, 7, MARKER),
+];
+
+const ROLES: [(&str, &str); 7] = [
+    ("#000001", "text"),
+    ("#000002", "axis"),
+    ("#000003", "grid"),
+    ("#000004", "background"),
+    ("#000005", "series-0"),
+    ("#000006", "series-1"),
+    ("#000007", "series-2"),
+];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Axis {
+    column: Option<String>,
+    label: Option<String>,
+    unit: Option<String>,
+    #[serde(default)]
+    log: bool,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Axis {
+    fn description(&self) -> String {
+        match (&self.label, &self.unit) {
+            (Some(label), Some(unit)) => format!("{label} ({unit})"),
+            (Some(label), None) => label.clone(),
+            (None, Some(unit)) => unit.clone(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SeriesKind {
+    #[default]
+    Line,
+    Scatter,
+    Bar,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Series {
+    column: String,
+    label: Option<String>,
+    #[serde(default)]
+    kind: SeriesKind,
+}
+
+impl Series {
+    fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.column)
+    }
+}
+
+fn default_width() -> u32 {
+    720
+}
+
+fn default_height() -> u32 {
+    405
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChartSpec {
+    data: String,
+    title: Option<String>,
+    description: Option<String>,
+    #[serde(default = "default_width")]
+    width: u32,
+    #[serde(default = "default_height")]
+    height: u32,
+    x: Axis,
+    y: Axis,
+    series: Vec<Series>,
+}
+
+pub struct Chart {
+    spec: ChartSpec,
+    labels: DiagramLabels,
+}
+
+impl Chart {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut spec: ChartSpec = toml::from_str(source)?;
+        if spec.x.column.is_none() {
+            bail!("the x axis must name a column");
+        }
+        if spec.y.column.is_some() {
+            bail!("the y axis takes its columns from each series");
+        }
+        if spec.series.is_empty() {
+            bail!("a chart needs at least one series");
+        }
+        if spec.x.log
+            && spec
+                .series
+                .iter()
+                .any(|series| series.kind == SeriesKind::Bar)
+        {
+            bail!("bar series need a linear x axis");
+        }
+        let labels = DiagramLabels::new(spec.title.take(), spec.description.take());
+        Ok(Self { spec, labels })
+    }
+
+    pub fn default_title(&mut self, title: &str) {
+        self.labels.default_title(title);
+    }
+}
+
+struct Table {
+    columns: HashMap<String, Vec<f64>>,
+}
+
+impl Table {
+    fn read(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => Self::from_csv(&source),
+            Some("json" | "json5") => Self::from_json(&source),
+            _ => bail!("chart data must be a .csv, .json or .json5 file"),
+        }
+    }
+
+    fn from_csv(source: &str) -> Result<Self> {
+        let mut columns: HashMap<String, Vec<f64>> = HashMap::new();
+        let mut reader = csv::Reader::from_reader(source.as_bytes());
+        let headers = reader.headers()?.clone();
+        for (row, record) in reader.records().enumerate() {
+            for (header, value) in headers.iter().zip(record?.iter()) {
+                let value = match value.trim() {
+                    "" => f64::NAN,
+                    value => value.parse().wrap_err_with(|| {
+                        format!("row {} column {header}: {value} is not a number", row + 1)
+                    })?,
+                };
+                columns.entry(header.to_string()).or_default().push(value);
+            }
+        }
+        Ok(Self { columns })
+    }
+
+    fn from_json(source: &str) -> Result<Self> {
+        let mut columns: HashMap<String, Vec<f64>> = HashMap::new();
+        let rows: Vec<serde_json::Map<String, serde_json::Value>> = json5::from_str(source)?;
+        let headers = rows
+            .iter()
+            .flat_map(|row| row.keys())
+            .cloned()
+            .collect::<Vec<_>>();
+        for (index, row) in rows.iter().enumerate() {
+            for header in &headers {
+                let value = match row.get(header) {
+                    None | Some(serde_json::Value::Null) => f64::NAN,
+                    Some(value) => value.as_f64().ok_or_else(|| {
+                        eyre!("row {} column {header}: {value} is not a number", index + 1)
+                    })?,
+                };
+                let column = columns.entry(header.clone()).or_default();
+                column.resize(index, f64::NAN);
+                column.push(value);
+            }
+        }
+        Ok(Self { columns })
+    }
+
+    fn column(&self, name: &str) -> Result<&[f64]> {
+        self.columns
+            .get(name)
+            .map(Vec::as_slice)
+            .ok_or_else(|| eyre!("data has no column named {name}"))
+    }
+
+    fn points(&self, x: &str, y: &str, x_log: bool, y_log: bool) -> Result<Vec<(f64, f64)>> {
+        let plottable = |value: f64, log: bool| value.is_finite() && (!log || value > 0.0);
+        Ok(self
+            .column(x)?
+            .iter()
+            .zip(self.column(y)?)
+            .map(|(x, y)| (*x, *y))
+            .filter(|(x, y)| plottable(*x, x_log) && plottable(*y, y_log))
+            .collect())
+    }
+}
+
+fn axis_range(
+    axis: &Axis,
+    values: impl Iterator<Item = f64>,
+    include_zero: bool,
+) -> Result<Range<f64>> {
+    let (mut min, mut max) = values
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+            (min.min(value), max.max(value))
+        });
+    if include_zero && !axis.log {
+        min = min.min(0.0);
+        max = max.max(0.0);
+    }
+    let (min, max) = (axis.min.unwrap_or(min), axis.max.unwrap_or(max));
+    if !min.is_finite() || !max.is_finite() {
+        bail!("no plottable values for axis {}", axis.description());
+    }
+    if axis.log && min <= 0.0 {
+        bail!("log axis {} must start above zero", axis.description());
+    }
+    if min < max {
+        Ok(min..max)
+    } else if axis.log {
+        Ok(min / 10.0..max * 10.0)
+    } else {
+        Ok(min - 1.0..max + 1.0)
+    }
+}
+
+struct Bars {
+    base: f64,
+    width: f64,
+    count: usize,
+}
+
+fn draw<X, Y>(
+    area: &DrawingArea<SVGBackend, Shift>,
+    chart: &Chart,
+    series: &[(&Series, Vec<(f64, f64)>)],
+    family: &str,
+    x_range: X,
+    y_range: Y,
+    bars: &Bars,
+) -> Result<()>
+where
+    X: AsRangedCoord<Value = f64>,
+    Y: AsRangedCoord<Value = f64>,
+    X::CoordDescType: ValueFormatter<f64>,
+    Y::CoordDescType: ValueFormatter<f64>,
+{
+    let text = (family, 14).into_font().color(&TEXT);
+    let mut context = ChartBuilder::on(area)
+        .margin(16)
+        .x_label_area_size(48)
+        .y_label_area_size(64)
+        .build_cartesian_2d(x_range, y_range)?;
+    context
+        .configure_mesh()
+        .x_desc(chart.spec.x.description())
+        .y_desc(chart.spec.y.description())
+        .label_style(text.clone())
+        .axis_desc_style(text.clone())
+        .axis_style(AXIS)
+        .bold_line_style(GRID)
+        .light_line_style(TRANSPARENT)
+        .draw()?;
+
+    let mut bar = 0.0;
+    for (index, (series, points)) in series.iter().enumerate() {
+        let color = SERIES[index % SERIES.len()];
+        let legend = match series.kind {
+            SeriesKind::Line => context.draw_series(LineSeries::new(
+                points.iter().copied(),
+                color.stroke_width(2),
+            ))?,
+            SeriesKind::Scatter => context.draw_series(
+                points
+                    .iter()
+                    .map(|point| Circle::new(*point, 3, color.filled())),
+            )?,
+            SeriesKind::Bar => {
+                let offset = (bar - (bars.count as f64 - 1.0) / 2.0) * bars.width;
+                bar += 1.0;
+                context.draw_series(points.iter().map(|(x, y)| {
+                    let left = x + offset - bars.width / 2.0;
+                    Rectangle::new([(left, bars.base), (left + bars.width, *y)], color.filled())
+                }))?
+            }
+        };
+        legend
+            .label(series.label())
+            .legend(move |(x, y)| Rectangle::new([(x, y - 4), (x + 16, y + 4)], color.filled()));
+    }
+    if series.len() > 1 {
+        context
+            .configure_series_labels()
+            .label_font(text)
+            .border_style(AXIS)
+            .background_style(BACKGROUND)
+            .position(SeriesLabelPosition::UpperLeft)
+            .draw()?;
+    }
+    area.present()?;
+    Ok(())
+}
+
+fn outline(chart: &Chart, series: &[(&Series, Vec<(f64, f64)>)]) -> Option<String> {
+    let mut outline = format!(
+        "Chart of {} against {}.",
+        series
+            .iter()
+            .map(|(series, _)| series.label())
+            .collect::<Vec<_>>()
+            .join(", "),
+        chart.spec.x.description()
+    );
+    let unit = chart.spec.y.unit.as_deref().unwrap_or_default();
+    for (series, points) in series {
+        let (min, max) = points
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), (_, y)| {
+                (min.min(*y), max.max(*y))
+            });
+        outline.push_str(&format!(
+            " {}: {} points from {min} to {max} {unit}.",
+            series.label(),
+            points.len()
+        ));
+    }
+    Some(outline.trim_end().to_string())
+}
+
+fn restyle(element: &mut Element) -> Result<()> {
+    let marker = MARKER.to_string();
+    let placeholder = element.attributes.get("opacity") == Some(&marker);
+    for attribute in ["fill", "stroke"] {
+        let Some(value) = element.attributes.get(attribute) else {
+            continue;
+        };
+        if value == "none" {
+            continue;
+        }
+        let role = ROLES
+            .iter()
+            .find(|(colour, _)| placeholder && colour == value)
+            .map(|(_, role)| *role)
+            .ok_or_else(|| eyre!("chart drew {value} outside the palette"))?;
+        element.attributes.remove(attribute);
+        let class = element.attributes.entry(String::from("class")).or_default();
+        if !class.is_empty() {
+            class.push(' ');
+        }
+        class.push_str(&format!("{role}-{attribute}"));
+    }
+    if placeholder {
+        element.attributes.remove("opacity");
+    }
+    for child in &mut element.children {
+        if let XMLNode::Element(child) = child {
+            restyle(child)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn compile_chart(
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+    chart: &Chart,
+) -> Result<String> {
+    let table =
+        Table::read(&content_reference.resolve_relative_content_path(config, &chart.spec.data))
+            .wrap_err_with(|| format!("in chart data {}", chart.spec.data))?;
+    let (x, y) = (&chart.spec.x, &chart.spec.y);
+    let x_column = x
+        .column
+        .as_deref()
+        .ok_or_eyre("the x axis must name a column")?;
+    let series = chart
+        .spec
+        .series
+        .iter()
+        .map(|series| {
+            Ok((
+                series,
+                table.points(x_column, &series.column, x.log, y.log)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let bar_points = series
+        .iter()
+        .filter(|(series, _)| series.kind == SeriesKind::Bar)
+        .map(|(_, points)| points)
+        .collect::<Vec<_>>();
+    // bars are centred on their x value and share the narrowest gap between neighbours
+    let gap = bar_points
+        .iter()
+        .flat_map(|points| points.windows(2).map(|pair| (pair[1].0 - pair[0].0).abs()))
+        .filter(|gap| *gap > 0.0)
+        .reduce(f64::min)
+        .unwrap_or(1.0);
+    let mut x_range = axis_range(
+        x,
+        series
+            .iter()
+            .flat_map(|(_, points)| points.iter().map(|(x, _)| *x)),
+        false,
+    )?;
+    if !bar_points.is_empty() {
+        x_range = x_range.start - gap / 2.0..x_range.end + gap / 2.0;
+    }
+    let y_range = axis_range(
+        y,
+        series
+            .iter()
+            .flat_map(|(_, points)| points.iter().map(|(_, y)| *y)),
+        !bar_points.is_empty(),
+    )?;
+    let bars = Bars {
+        base: if y.log { y_range.start } else { 0.0 },
+        width: gap * 0.8 / bar_points.len().max(1) as f64,
+        count: bar_points.len(),
+    };
+
+    let family = config.get_fontbook().regular_family()?;
+    let mut svg = String::new();
+    {
+        let area = SVGBackend::with_string(&mut svg, (chart.spec.width, chart.spec.height))
+            .into_drawing_area();
+        match (x.log, y.log) {
+            (false, false) => draw(&area, chart, &series, &family, x_range, y_range, &bars),
+            (true, false) => draw(
+                &area,
+                chart,
+                &series,
+                &family,
+                x_range.log_scale(),
+                y_range,
+                &bars,
+            ),
+            (false, true) => draw(
+                &area,
+                chart,
+                &series,
+                &family,
+                x_range,
+                y_range.log_scale(),
+                &bars,
+            ),
+            (true, true) => draw(
+                &area,
+                chart,
+                &series,
+                &family,
+                x_range.log_scale(),
+                y_range.log_scale(),
+                &bars,
+            ),
+        }?;
+    }
+
+    let mut root = Element::parse(svg.as_bytes())?;
+    restyle(&mut root)?;
+    root.children.push(XMLNode::Element(themed_style(|palette| {
+        let mut css = format!(
+            ".text-fill{{fill:{}}}.axis-stroke{{stroke:{}}}.grid-stroke{{stroke:{}}}.background-fill{{fill:{}}}",
+            palette.text, palette.stroke, palette.grid, palette.background
+        );
+        for (index, color) in palette.series.iter().enumerate() {
+            css.push_str(&format!(
+                ".series-{index}-fill{{fill:{color}}}.series-{index}-stroke{{stroke:{color}}}"
+            ));
+        }
+        css
+    })));
+    chart.labels.apply(&mut root, |_| outline(chart, &series));
+    let mut destination = Vec::new();
+    root.write(&mut destination)?;
+    Ok(String::from_utf8(destination)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+data = "data.csv"
+x = { column = "frequency", unit = "Hz" }
+y = { unit = "V" }
+series = [
+    { column = "primary" },
+    { column = "secondary", kind = "bar" },
+]
+"#;
+
+    #[test]
+    fn csv_table() -> Result<()> {
+        let table = Table::from_csv("frequency,primary\n1,2.5\n2,\n3,-1e3\n")?;
+        assert_eq!(table.column("frequency")?, [1.0, 2.0, 3.0]);
+        assert!(table.column("primary")?[1].is_nan());
+        assert_eq!(
+            table.points("frequency", "primary", false, false)?,
+            [(1.0, 2.5), (3.0, -1000.0)]
+        );
+        assert!(table.points("frequency", "primary", false, true)?.len() == 1);
+        assert!(table.column("secondary").is_err());
+        assert!(Table::from_csv("frequency\nten\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn json5_table() -> Result<()> {
+        let table = Table::from_json(
+            "[
+                // the first reading had no secondary
+                { frequency: 1, primary: 2.5 },
+                { frequency: 2, primary: null, secondary: 4 },
+                { frequency: 3, secondary: 5, },
+            ]",
+        )?;
+        assert_eq!(table.column("frequency")?, [1.0, 2.0, 3.0]);
+        let primary = table.column("primary")?;
+        assert_eq!(primary[0], 2.5);
+        assert!(primary[1].is_nan() && primary[2].is_nan());
+        let secondary = table.column("secondary")?;
+        assert!(secondary[0].is_nan());
+        assert_eq!(secondary[1..], [4.0, 5.0]);
+        assert!(Table::from_json(r#"[{ frequency: "ten" }]"#).is_err());
+        assert!(Table::from_json("{ frequency: 1 }").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn roles_become_classes() -> Result<()> {
+        let chart = Chart::parse(SPEC)?;
+        let table = Table::from_csv("frequency,primary,secondary\n1,2,3\n2,3,4\n")?;
+        let series = chart
+            .spec
+            .series
+            .iter()
+            .map(|series| {
+                Ok((
+                    series,
+                    table.points("frequency", &series.column, false, false)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let bars = Bars {
+            base: 0.0,
+            width: 0.4,
+            count: 1,
+        };
+        let mut svg = String::new();
+        {
+            let area = SVGBackend::with_string(&mut svg, (320, 200)).into_drawing_area();
+            draw(
+                &area,
+                &chart,
+                &series,
+                "sans-serif",
+                0.5..2.5,
+                0.0..4.0,
+                &bars,
+            )?;
+        }
+        let mut root = Element::parse(svg.as_bytes())?;
+        restyle(&mut root)?;
+        let mut restyled = Vec::new();
+        root.write(&mut restyled)?;
+        let restyled = String::from_utf8(restyled)?;
+        for class in [
+            "text-fill",
+            "axis-stroke",
+            "grid-stroke",
+            "series-0-stroke",
+            "series-1-fill",
+        ] {
+            assert!(restyled.contains(class), "{class} is missing");
+        }
+        assert!(!restyled.contains("#00000"));
+        assert!(!restyled.contains(&format!(r#"opacity="{MARKER}""#)));
+        Ok(())
+    }
+
+    #[test]
+    fn colours_outside_the_palette() -> Result<()> {
+        // a placeholder's colour without its opacity is not a placeholder
+        let mut root = Element::parse(
+            r##"<svg><rect fill="#000005" stroke="none" opacity="1"/></svg>"##.as_bytes(),
+        )?;
+        assert!(restyle(&mut root).is_err());
+        let mut root = Element::parse(
+            format!(r##"<svg><rect fill="#000005" stroke="none" opacity="{MARKER}"/></svg>"##)
+                .as_bytes(),
+        )?;
+        restyle(&mut root)?;
+        let rect = root.get_child("rect").ok_or_eyre("rect is missing")?;
+        assert_eq!(
+            rect.attributes.get("class").map(String::as_str),
+            Some("series-0-fill")
+        );
+        assert!(!rect.attributes.contains_key("opacity"));
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+use std::ops::RangeInclusive;
+
+use color_eyre::eyre::{Result, bail, eyre};
+
+use crate::{shortcodes::Arguments, util::escape_html};
+
+pub struct CodeAttributes {
+    title: Option<String>,
+    download: Option<String>,
+    linenos: bool,
+    anchors: bool,
+    start: usize,
+    highlighted: Vec<RangeInclusive<usize>>,
+}
+
+fn parse_line_ranges(source: &str) -> Result<Vec<RangeInclusive<usize>>> {
+    source
+        .split([',', ' '])
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let parse = |number: &str| {
+                number
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| eyre!("{range} is not a line or range of lines"))
+            };
+            let (first, last) = match range.split_once('-') {
+                Some((first, last)) => (parse(first)?, parse(last)?),
+                None => (parse(range)?, parse(range)?),
+            };
+            if last < first {
+                bail!("line range {range} ends before it starts");
+            }
+            Ok(first..=last)
+        })
+        .collect()
+}
+
+impl CodeAttributes {
+    pub fn from_arguments(args: &mut Arguments) -> Result<Self> {
+        let start = args.optional("start");
+        Ok(Self {
+            title: args.optional("title"),
+            download: args.optional("download"),
+            linenos: args.parsed("linenos", start.is_some())?,
+            anchors: false,
+            start: start
+                .map(|start| {
+                    start
+                        .parse()
+                        .map_err(|_| eyre!("argument start has invalid value {start}"))
+                })
+                .transpose()?
+                .unwrap_or(1),
+            highlighted: args
+                .optional("hl_lines")
+                .map(|lines| parse_line_ranges(&lines))
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+
+    // whole-file listings, where each line number links to itself
+    pub fn anchored() -> Self {
+        Self {
+            title: None,
+            download: None,
+            linenos: true,
+            anchors: true,
+            start: 1,
+            highlighted: Vec::new(),
+        }
+    }
+
+    // highlighted ranges count in the displayed numbering, so start=120 pairs with hl_lines="123-127"
+    pub fn render(&self, lang: &str, lines: &[String]) -> Result<String> {
+        let last = self.start + lines.len().saturating_sub(1);
+        for range in &self.highlighted {
+            if *range.start() < self.start || *range.end() > last {
+                bail!(
+                    "highlighted lines {}-{} are outside lines {}-{last}",
+                    range.start(),
+                    range.end(),
+                    self.start
+                );
+            }
+        }
+        let mut html = String::new();
+        let captioned = self.title.is_some() || self.download.is_some();
+        if captioned {
+            html.push_str(r#"<figure class="code"><figcaption>"#);
+            if let Some(title) = &self.title {
+                html.push_str(&escape_html(title));
+            }
+            if let Some(download) = &self.download {
+                html.push_str(&format!(
+                    r#" <a href="{}" download>Download full file</a>"#,
+                    escape_html(download)
+                ));
+            }
+            html.push_str("</figcaption>");
+        }
+        if self.linenos {
+            html.push_str(&format!(
+                r#"<pre class="linenos" style="--lineno-digits: {}">"#,
+                last.to_string().len()
+            ));
+        } else {
+            html.push_str("<pre>");
+        }
+        html.push_str(&format!(r#"<code class="language-{}">"#, escape_html(lang)));
+        let wrap = self.linenos || !self.highlighted.is_empty();
+        for (index, line) in lines.iter().enumerate() {
+            if !wrap {
+                html.push_str(line);
+                html.push('\n');
+                continue;
+            }
+            let number = self.start + index;
+            let class = if self.highlighted.iter().any(|range| range.contains(&number)) {
+                "line highlighted"
+            } else {
+                "line"
+            };
+            html.push_str(&format!(r#"<span class="{class}""#));
+            if self.anchors {
+                html.push_str(&format!(
+                    r##" id="L{number}"><a class="lineno" href="#L{number}">{number}</a>"##
+                ));
+            } else if self.linenos {
+                html.push_str(&format!(r#" data-line="{number}">"#));
+            } else {
+                html.push('>');
+            }
+            html.push_str(&format!("{line}</span>\n"));
+        }
+        html.push_str("</code></pre>");
+        if captioned {
+            html.push_str("</figure>");
+        }
+        Ok(html)
+    }
+}
+
+// syntect spans can run across lines, so each line closes and reopens whatever is still open
+pub fn split_highlighted_lines(html: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut open: Vec<&str> = Vec::new();
+    let mut line = String::new();
+    let mut has_text = false;
+    let mut remainder = html;
+    while let Some(position) = remainder.find(['<', '\n']) {
+        line.push_str(&remainder[..position]);
+        has_text |= position > 0;
+        remainder = &remainder[position..];
+        if let Some(rest) = remainder.strip_prefix('\n') {
+            line.push_str(&"</span>".repeat(open.len()));
+            lines.push(std::mem::take(&mut line));
+            line.push_str(&open.concat());
+            has_text = false;
+            remainder = rest;
+        } else if let Some(rest) = remainder.strip_prefix("</span>") {
+            open.pop();
+            line.push_str("</span>");
+            remainder = rest;
+        } else {
+            let end = remainder.find('>').map_or(remainder.len(), |end| end + 1);
+            let tag = &remainder[..end];
+            if tag.starts_with("<span") {
+                open.push(tag);
+            }
+            line.push_str(tag);
+            remainder = &remainder[end..];
+        }
+    }
+    line.push_str(remainder);
+    if has_text || !remainder.is_empty() {
+        line.push_str(&"</span>".repeat(open.len()));
+        lines.push(line);
+    }
+    lines
+}
+
+#[derive(Clone, Copy)]
+enum Change {
+    Added,
+    Removed,
+    Unchanged,
+}
+
+impl Change {
+    fn marker(self) -> char {
+        match self {
+            Self::Added => '+',
+            Self::Removed => '-',
+            Self::Unchanged => ' ',
+        }
+    }
+}
+
+enum DiffLine<T> {
+    Code(Change, T),
+    Header(String),
+}
+
+// the line counts from a hunk header such as @@ -1,4 +1,5 @@, where a missing count means one line
+fn hunk_lengths(header: &str) -> Option<(usize, usize)> {
+    let mut ranges = header.strip_prefix("@@ ")?.split_whitespace();
+    let mut length = |prefix| -> Option<usize> {
+        match ranges.next()?.strip_prefix(prefix)?.split_once(',') {
+            Some((_, length)) => length.parse().ok(),
+            None => Some(1),
+        }
+    };
+    Some((length('-')?, length('+')?))
+}
+
+// each side of a unified diff is highlighted whole, so strings and comments spanning lines stay intact
+pub struct Diff {
+    lines: Vec<DiffLine<()>>,
+    pub before: String,
+    pub after: String,
+}
+
+impl Diff {
+    pub fn parse(source: &str) -> Self {
+        let mut lines = Vec::new();
+        let mut before = String::new();
+        let mut after = String::new();
+        // lines left on each side of the current hunk, from its @@ header
+        let mut remaining = (0, 0);
+        // git's extended headers run from a diff line to the first hunk
+        let mut in_header = false;
+        let mut source = source.lines().peekable();
+        while let Some(line) = source.next() {
+            let in_hunk = remaining != (0, 0);
+            let header = if line.starts_with("@@") {
+                remaining = hunk_lengths(line).unwrap_or((usize::MAX, usize::MAX));
+                in_header = false;
+                true
+            } else if in_hunk {
+                false
+            } else if line.starts_with("diff ") {
+                in_header = true;
+                true
+            } else {
+                in_header
+                    || line.starts_with("index ")
+                    || (line.starts_with("--- ")
+                        && source.peek().is_some_and(|next| next.starts_with("+++ ")))
+                    || (line.starts_with("+++ ")
+                        && matches!(lines.last(), Some(DiffLine::Header(previous)) if previous.starts_with("--- ")))
+            };
+            let (change, rest) = if header {
+                lines.push(DiffLine::Header(line.to_string()));
+                continue;
+            } else if line.starts_with('\\') {
+                // "\ No newline at end of file"
+                continue;
+            } else if let Some(rest) = line.strip_prefix('+') {
+                (Change::Added, rest)
+            } else if let Some(rest) = line.strip_prefix('-') {
+                (Change::Removed, rest)
+            } else {
+                (Change::Unchanged, line.strip_prefix(' ').unwrap_or(line))
+            };
+            let (old, new) = &mut remaining;
+            for (count, counted) in [
+                (old, !matches!(change, Change::Added)),
+                (new, !matches!(change, Change::Removed)),
+            ] {
+                if counted {
+                    *count = count.saturating_sub(1);
+                }
+            }
+            lines.push(DiffLine::Code(change, ()));
+            for (side, included) in [
+                (&mut before, !matches!(change, Change::Added)),
+                (&mut after, !matches!(change, Change::Removed)),
+            ] {
+                if included {
+                    side.push_str(rest);
+                    side.push('\n');
+                }
+            }
+        }
+        Self {
+            lines,
+            before,
+            after,
+        }
+    }
+
+    fn zip<'a>(
+        &'a self,
+        before: &'a [String],
+        after: &'a [String],
+    ) -> impl Iterator<Item = DiffLine<&'a str>> {
+        let mut before = before.iter().map(String::as_str);
+        let mut after = after.iter().map(String::as_str);
+        self.lines.iter().map(move |line| match line {
+            DiffLine::Code(change, ()) => {
+                let code = match change {
+                    Change::Added => after.next(),
+                    Change::Removed => before.next(),
+                    Change::Unchanged => {
+                        before.next();
+                        after.next()
+                    }
+                };
+                DiffLine::Code(*change, code.unwrap_or_default())
+            }
+            DiffLine::Header(header) => DiffLine::Header(escape_html(header)),
+        })
+    }
+
+    pub fn render_html(&self, before: &[String], after: &[String]) -> String {
+        let mut html = String::new();
+        for line in self.zip(before, after) {
+            match line {
+                DiffLine::Code(change, code) => {
+                    let class = match change {
+                        Change::Added => "line added",
+                        Change::Removed => "line removed",
+                        Change::Unchanged => "line",
+                    };
+                    html.push_str(&format!(
+                        r#"<span class="{class}"><span class="gutter">{}</span>{code}</span>"#,
+                        change.marker()
+                    ));
+                }
+                DiffLine::Header(header) => {
+                    html.push_str(&format!(r#"<span class="line hunk">{header}</span>"#));
+                }
+            }
+            html.push('\n');
+        }
+        html
+    }
+
+    pub fn render_lines(&self, before: &[String], after: &[String]) -> Vec<String> {
+        self.zip(before, after)
+            .map(|line| match line {
+                DiffLine::Code(change, code) => format!("{}{code}", change.marker()),
+                DiffLine::Header(header) => header,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markers(diff: &Diff) -> String {
+        diff.lines
+            .iter()
+            .map(|line| match line {
+                DiffLine::Code(change, ()) => change.marker(),
+                DiffLine::Header(_) => 'h',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn multiple_files() {
+        let diff = Diff::parse(
+            "diff --git a/one.sql b/one.sql
+index 83db48f..bf269f4 100644
+--- a/one.sql
++++ b/one.sql
+@@ -1,2 +1,2 @@
+ select 1;
+--- old comment
++-- new comment
+diff --git a/two.sql b/two.sql
+new file mode 100644
+index 0000000..bf269f4
+--- /dev/null
++++ b/two.sql
+@@ -0,0 +1 @@
++select 2;
+",
+        );
+        assert_eq!(markers(&diff), "hhhhh -+hhhhhh+");
+        assert_eq!(diff.before, "select 1;\n-- old comment\n");
+        assert_eq!(diff.after, "select 1;\n-- new comment\nselect 2;\n");
+    }
+
+    #[test]
+    fn without_headers() {
+        let diff = Diff::parse(" kept\n-gone\n+added\n");
+        assert_eq!(markers(&diff), " -+");
+    }
+
+    fn lines(count: usize) -> Vec<String> {
+        (1..=count).map(|line| format!("line {line}")).collect()
+    }
+
+    #[test]
+    fn line_ranges() -> Result<()> {
+        assert_eq!(parse_line_ranges("1,3-5")?, vec![1..=1, 3..=5]);
+        assert_eq!(parse_line_ranges("2 4-4, 7")?, vec![2..=2, 4..=4, 7..=7]);
+        assert!(parse_line_ranges("")?.is_empty());
+        for source in ["5-3", "a", "1-", "-2"] {
+            assert!(parse_line_ranges(source).is_err(), "{source} was accepted");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn start_numbers_lines() -> Result<()> {
+        let mut args = Arguments::parse_info(r#"start=120 hl_lines="121-122""#)?;
+        let attributes = CodeAttributes::from_arguments(&mut args)?;
+        args.finish()?;
+        assert!(attributes.linenos);
+        let html = attributes.render("rust", &lines(4))?;
+        assert!(html.contains(r#"<span class="line" data-line="120">line 1</span>"#));
+        assert!(html.contains(r#"<span class="line highlighted" data-line="121">line 2</span>"#));
+        assert!(html.contains(r#"<span class="line highlighted" data-line="122">line 3</span>"#));
+        assert!(html.contains(r#"<span class="line" data-line="123">line 4</span>"#));
+        Ok(())
+    }
+
+    #[test]
+    fn highlights_without_numbers() -> Result<()> {
+        let mut args = Arguments::parse_info(r#"hl_lines="2""#)?;
+        let attributes = CodeAttributes::from_arguments(&mut args)?;
+        assert!(!attributes.linenos);
+        let html = attributes.render("rust", &lines(2))?;
+        assert!(html.starts_with("<pre>"));
+        assert!(html.contains(r#"<span class="line">line 1</span>"#));
+        assert!(html.contains(r#"<span class="line highlighted">line 2</span>"#));
+        Ok(())
+    }
+
+    #[test]
+    fn highlights_outside_listing() -> Result<()> {
+        for hl_lines in ["1", "119-120", "124"] {
+            let mut args = Arguments::parse_info(&format!(r#"start=120 hl_lines="{hl_lines}""#))?;
+            let attributes = CodeAttributes::from_arguments(&mut args)?;
+            assert!(
+                attributes.render("rust", &lines(4)).is_err(),
+                "{hl_lines} was accepted"
+            );
+        }
+        assert!(CodeAttributes::from_arguments(&mut Arguments::parse_info("start=x")?).is_err());
+        Ok(())
+    }
+}
@@ -2,20 +2,34 @@ use chrono::{DateTime, Utc};
 use chrono_tz::{Canada, Tz};
 use color_eyre::eyre::Result;
 use itertools::Itertools;
+use sha2::{Digest, Sha256};
 use std::{
+    collections::HashSet,
     fmt::Display,
-    fs::read_to_string,
+    fs::{metadata, read_to_string},
     path::{Path, PathBuf, StripPrefixError},
+    process::Command,
+    sync::{Mutex, OnceLock, PoisonError},
 };
+use tracing::warn;
 
-use crate::{markdown::Metadata, util::FontBook};
+use crate::{
+    attachments::{Attachment, scan_attachments},
+    markdown::{ChangeFrequency, MarkdownDocument, Metadata},
+    util::FontBook,
+};
 
 pub struct SiteConfiguration {
     root_content_path: PathBuf,
     root_output_path: PathBuf,
     root_url: String,
-    d2: PathBuf,
+    d2: Option<PathBuf>,
+    d2_version: OnceLock<Option<String>>,
+    d2_cache: Option<PathBuf>,
+    d2_cache_used: Mutex<HashSet<PathBuf>>,
+    d2_fonts_hash: OnceLock<Vec<u8>>,
     fontbook: FontBook,
+    attachments: OnceLock<Vec<Attachment>>,
 }
 
 impl SiteConfiguration {
@@ -23,7 +37,8 @@ impl SiteConfiguration {
         root_content_path: PathBuf,
         root_output_path: PathBuf,
         root_url: String,
-        d2: PathBuf,
+        d2: Option<PathBuf>,
+        d2_cache: Option<PathBuf>,
         fontbook: FontBook,
     ) -> Self {
         Self {
@@ -31,12 +46,80 @@ impl SiteConfiguration {
             root_output_path,
             root_url,
             d2,
+            d2_version: OnceLock::new(),
+            d2_cache,
+            d2_cache_used: Mutex::new(HashSet::new()),
+            d2_fonts_hash: OnceLock::new(),
             fontbook,
+            attachments: OnceLock::new(),
+        }
+    }
+
+    pub fn get_d2(&self) -> Option<(&Path, &str)> {
+        let executable = self.d2.as_deref()?;
+        let version = self.d2_version.get_or_init(|| {
+            match Command::new(executable).arg("--version").output() {
+                Ok(output) if output.status.success() => {
+                    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+                }
+                Ok(_) | Err(_) => {
+                    warn!("d2 at {} is unavailable", executable.to_string_lossy());
+                    None
+                }
+            }
+        });
+        version.as_deref().map(|version| (executable, version))
+    }
+
+    pub fn get_d2_cache(&self) -> Option<&Path> {
+        self.d2_cache.as_deref()
+    }
+
+    pub fn use_d2_cache_entry(&self, path: &Path) {
+        self.d2_cache_used
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(path.to_path_buf());
+    }
+
+    pub fn is_d2_cache_entry_used(&self, path: &Path) -> bool {
+        self.d2_cache_used
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(path)
+    }
+
+    pub fn get_d2_fonts_hash(&self) -> Result<&[u8]> {
+        if let Some(hash) = self.d2_fonts_hash.get() {
+            return Ok(hash);
+        }
+        let mut hasher = Sha256::new();
+        for font in [
+            &self.fontbook.regular,
+            &self.fontbook.italic,
+            &self.fontbook.bold,
+            &self.fontbook.semibold,
+        ] {
+            hasher.update(std::fs::read(font)?);
+        }
+        Ok(self
+            .d2_fonts_hash
+            .get_or_init(|| hasher.finalize().to_vec()))
+    }
+
+    pub fn get_attachments(&self) -> Result<&[Attachment]> {
+        if let Some(attachments) = self.attachments.get() {
+            return Ok(attachments);
         }
+        let attachments = scan_attachments(&self.root_content_path)?;
+        Ok(self.attachments.get_or_init(|| attachments))
     }
 
-    pub fn get_d2_executable(&self) -> &Path {
-        &self.d2
+    pub fn find_attachment(&self, path: &Path) -> Result<Option<&Attachment>> {
+        Ok(self
+            .get_attachments()?
+            .iter()
+            .find(|attachment| attachment.path == path))
     }
 
     pub fn get_site_url(&self) -> &str {
@@ -60,6 +143,25 @@ impl SiteConfiguration {
     }
 }
 
+#[cfg(test)]
+pub fn test_configuration() -> SiteConfiguration {
+    let font = PathBuf::from("font.ttf");
+    SiteConfiguration::new(
+        PathBuf::from("content"),
+        PathBuf::from("output"),
+        String::from("https://example.com/"),
+        None,
+        None,
+        FontBook {
+            regular: font.clone(),
+            italic: font.clone(),
+            bold: font.clone(),
+            semibold: font.clone(),
+            monospace: font,
+        },
+    )
+}
+
 pub struct ContentReference {
     content_file_path: PathBuf,
     output_file_path: PathBuf,
@@ -92,6 +194,17 @@ impl ContentReference {
         self.output_file_path.to_string_lossy().to_string()
     }
 
+    // source code attachments are published raw and alongside a highlighted viewer page
+    pub fn is_attachment(&self) -> bool {
+        self.content_file_path
+            .parent()
+            .is_some_and(|parent| parent.iter().any(|directory| directory == "attachments"))
+    }
+
+    pub fn get_viewer_url(&self) -> String {
+        format!("{}.html", self.get_relative_url())
+    }
+
     pub fn get_full_url(&self, site_config: &SiteConfiguration) -> String {
         format!(
             "{}{}",
@@ -120,6 +233,58 @@ impl ContentReference {
             .join(p)
     }
 
+    // checkouts reset mtimes, so the last commit to touch the file is preferred; files git does not
+    // know about yet fall back to their mtime
+    pub fn get_last_modified(&self, site_config: &SiteConfiguration) -> Result<DateTime<Tz>> {
+        let path = self.get_source_path(site_config);
+        let committed = Command::new("git")
+            .arg("-C")
+            .arg(path.parent().unwrap_or(Path::new(".")))
+            .args(["log", "-1", "--format=%cI", "--"])
+            .arg(&path)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| {
+                DateTime::parse_from_rfc3339(String::from_utf8_lossy(&output.stdout).trim()).ok()
+            });
+        Ok(match committed {
+            Some(committed) => committed.with_timezone(&Canada::Eastern),
+            None => {
+                DateTime::<Utc>::from(metadata(path)?.modified()?).with_timezone(&Canada::Eastern)
+            }
+        })
+    }
+
+    pub fn get_source_path(&self, site_config: &SiteConfiguration) -> PathBuf {
+        site_config.root_content_path.join(&self.content_file_path)
+    }
+
+    pub fn read(&self, site_config: &SiteConfiguration) -> Result<String> {
+        Ok(read_to_string(self.get_source_path(site_config))?)
+    }
+
+    pub fn write(&self, site_config: &SiteConfiguration, output: impl AsRef<[u8]>) -> Result<()> {
+        std::fs::write(
+            site_config.root_output_path.join(&self.output_file_path),
+            output,
+        )?;
+        Ok(())
+    }
+
+    pub fn resolve_relative_content_path(
+        &self,
+        site_config: &SiteConfiguration,
+        p: impl AsRef<Path>,
+    ) -> PathBuf {
+        site_config
+            .root_content_path
+            .join(self.content_file_path.parent().expect(
+                "content_file_path should always contain a file path, so parent is always findable",
+            ))
+            .join(p)
+    }
+
     pub fn process_with_output<F, R>(
         &mut self,
         site_config: &SiteConfiguration,
@@ -128,12 +293,9 @@ impl ContentReference {
     where
         F: FnOnce(String, &SiteConfiguration, &Self) -> Result<(String, R)>,
     {
-        let input = read_to_string(site_config.root_content_path.join(&self.content_file_path))?;
+        let input = self.read(site_config)?;
         let (output, result) = processor(input, site_config, self)?;
-        std::fs::write(
-            site_config.root_output_path.join(&self.output_file_path),
-            output,
-        )?;
+        self.write(site_config, output)?;
         Ok(result)
     }
 
@@ -168,12 +330,12 @@ impl Display for ContentReference {
 }
 
 pub struct Sitemap {
-    entries: Vec<(ContentReference, String, Metadata)>,
+    entries: Vec<(ContentReference, MarkdownDocument)>,
     buildstamp: DateTime<Tz>,
 }
 
 impl Sitemap {
-    pub fn new(entries: Vec<(ContentReference, String, Metadata)>) -> Self {
+    pub fn new(entries: Vec<(ContentReference, MarkdownDocument)>) -> Self {
         Self {
             entries,
             buildstamp: Utc::now().with_timezone(&Canada::Eastern),
@@ -186,15 +348,15 @@ impl Sitemap {
     ) -> impl Iterator<Item = ArticleEntry<'a>> {
         self.entries
             .iter()
-            .filter_map(|(content_reference, title, metadata)| {
+            .filter_map(|(content_reference, document)| {
                 if let Metadata::Article {
                     date,
                     modified: _,
                     category,
-                } = metadata
+                } = &document.metadata
                 {
                     Some(ArticleEntry {
-                        title,
+                        title: &document.title,
                         relative_url: content_reference.get_relative_url(),
                         listing_url: format!(
                             "/images/listing_{}.jpeg",
@@ -217,21 +379,53 @@ impl Sitemap {
     ) -> impl Iterator<Item = MapEntry> {
         self.entries
             .iter()
-            .map(|(content_reference, _title, metadata)| match metadata {
-                Metadata::Article {
-                    date,
-                    modified,
-                    category: _,
-                } => MapEntry {
-                    location: content_reference.get_full_url(site_config),
-                    last_modified: modified.unwrap_or(*date).to_owned(),
-                    change_frequency: String::from("monthly"),
-                },
-                Metadata::Page { description: _ } => MapEntry {
+            .filter(|(_, document)| document.indexing.in_sitemap())
+            .map(|(content_reference, document)| {
+                let (last_modified, change_frequency) = match &document.metadata {
+                    Metadata::Article {
+                        date,
+                        modified,
+                        category: _,
+                    } => (
+                        modified.unwrap_or(*date).to_owned(),
+                        ChangeFrequency::Monthly,
+                    ),
+                    Metadata::Page {
+                        description: _,
+                        modified,
+                    } => (modified.unwrap_or(self.buildstamp), ChangeFrequency::Yearly),
+                };
+                MapEntry {
                     location: content_reference.get_full_url(site_config),
-                    last_modified: self.buildstamp,
-                    change_frequency: String::from("yearly"),
-                },
+                    last_modified,
+                    change_frequency: document
+                        .indexing
+                        .change_frequency
+                        .unwrap_or(change_frequency),
+                    priority: document.indexing.priority.unwrap_or(0.5),
+                }
+            })
+    }
+
+    pub fn get_template_pages(
+        &self,
+    ) -> impl Iterator<Item = (&ContentReference, &MarkdownDocument)> {
+        self.entries
+            .iter()
+            .filter(|(_, document)| document.template.is_some())
+            .map(|(content_reference, document)| (content_reference, document))
+    }
+
+    // pages with an order come first, in that order, and the rest follow by title
+    pub fn get_template_entries(&self) -> impl Iterator<Item = TemplateEntry<'_>> {
+        self.get_template_pages()
+            .sorted_by_key(|(_, document)| {
+                (document.order.is_none(), document.order, &document.title)
+            })
+            .map(|(content_reference, document)| TemplateEntry {
+                title: &document.title,
+                relative_url: content_reference.get_relative_url(),
+                summary: &document.content,
             })
     }
 
@@ -243,7 +437,8 @@ impl Sitemap {
 pub struct MapEntry {
     pub location: String,
     pub last_modified: DateTime<Tz>,
-    pub change_frequency: String,
+    pub change_frequency: ChangeFrequency,
+    pub priority: f32,
 }
 
 pub struct ArticleEntry<'a> {
@@ -254,3 +449,9 @@ pub struct ArticleEntry<'a> {
     pub date: &'a DateTime<Tz>,
     pub category: &'a String,
 }
+
+pub struct TemplateEntry<'a> {
+    pub title: &'a str,
+    pub relative_url: String,
+    pub summary: &'a str,
+}
@@ -1,18 +1,28 @@
+use base64::{Engine, prelude::BASE64_STANDARD};
 use color_eyre::{
     Result,
-    eyre::{OptionExt, eyre},
+    eyre::{OptionExt, WrapErr, bail, eyre},
 };
 use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
 use std::{
     ffi::{OsStr, OsString},
-    io::{Cursor, Read, Write},
+    fmt::Display,
+    fs::create_dir_all,
+    io::{Cursor, Write},
+    path::Path,
     process::{Command, Stdio},
     sync::LazyLock,
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use walkdir::WalkDir;
 use xmltree::{Element, XMLNode};
 
-use crate::content::SiteConfiguration;
+use crate::{
+    content::SiteConfiguration,
+    palette::{Scheme, property},
+    shortcodes::Arguments,
+};
 
 static PATCH_FONT_STYLE: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new(r#""?d2-\d+-font-(?P<style>regular|bold|italic|semibold)"?;"#)
@@ -48,41 +58,480 @@ fn walk_to_styles(node: &mut XMLNode) -> Result<()> {
     Ok(())
 }
 
-fn postprocess_svg_css<R, W>(input: R, output: W) -> Result<()>
-where
-    R: Read,
-    W: Write,
-{
-    let mut root = Element::parse(input)?;
+fn postprocess_svg_css(root: &mut Element) -> Result<()> {
     for node in &mut root.children {
         walk_to_styles(node)?;
     }
-    root.write(output)?;
     Ok(())
 }
 
-pub fn compile_d2(config: &SiteConfiguration, source: &[u8]) -> Result<String> {
-    let mut d2 = Command::new(config.get_d2_executable())
-        .arg("--theme=1")
-        .arg("--dark-theme=1")
-        .arg(OsString::from_iter([
-            OsStr::new("--font-regular="),
-            config.get_fontbook().regular.as_os_str(),
-        ]))
-        .arg(OsString::from_iter([
-            OsStr::new("--font-italic="),
-            config.get_fontbook().italic.as_os_str(),
-        ]))
-        .arg(OsString::from_iter([
-            OsStr::new("--font-bold="),
-            config.get_fontbook().bold.as_os_str(),
-        ]))
-        .arg(OsString::from_iter([
-            OsStr::new("--font-semibold="),
-            config.get_fontbook().semibold.as_os_str(),
-        ]))
-        .arg("--pad=0")
+static D2_CONNECTION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:(?P<scope>.+)\.)?\((?P<from>.+?) (?P<arrow><->|->|<-|--) (?P<to>.+)\)\[\d+\]$")
+        .expect("compile time regex invalid")
+});
+
+fn element_text(element: &Element) -> String {
+    element
+        .children
+        .iter()
+        .filter_map(|child| match child {
+            XMLNode::Text(text) => Some(text.trim().to_string()),
+            XMLNode::Element(child) => Some(element_text(child)),
+            _ => None,
+        })
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn d2_object_id(element: &Element) -> Option<String> {
+    // d2 tags each shape and connection group with its base64 encoded, html escaped id
+    let class = element.attributes.get("class")?.split_whitespace().next()?;
+    let id = String::from_utf8(BASE64_STANDARD.decode(class).ok()?).ok()?;
+    if id.chars().any(char::is_control) {
+        return None;
+    }
+    Some(
+        id.replace("&gt;", ">")
+            .replace("&lt;", "<")
+            .replace("&#34;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+fn collect_d2_objects(element: &Element, objects: &mut Vec<(String, String)>) {
+    for child in &element.children {
+        if let XMLNode::Element(child) = child {
+            match d2_object_id(child).filter(|_| child.matches("g")) {
+                Some(id) if !objects.iter().any(|(seen, _)| *seen == id) => {
+                    objects.push((id, element_text(child)));
+                }
+                Some(_) => {}
+                None => collect_d2_objects(child, objects),
+            }
+        }
+    }
+}
+
+fn d2_outline(root: &Element) -> Option<String> {
+    let mut objects = Vec::new();
+    collect_d2_objects(root, &mut objects);
+    let label = |id: &str| {
+        objects
+            .iter()
+            .find(|(object, _)| object == id)
+            .map(|(_, label)| label.as_str())
+            .filter(|label| !label.is_empty())
+            .map(str::to_string)
+            .unwrap_or_else(|| id.rsplit('.').next().unwrap_or(id).to_string())
+    };
+    let mut shapes = Vec::new();
+    let mut connections = Vec::new();
+    for (id, text) in &objects {
+        let Some(captures) = D2_CONNECTION.captures(id) else {
+            shapes.push(label(id));
+            continue;
+        };
+        let scoped = |name: &str| match captures.name("scope") {
+            Some(scope) => label(&format!("{}.{name}", scope.as_str())),
+            None => label(name),
+        };
+        let (from, to) = (scoped(&captures["from"]), scoped(&captures["to"]));
+        let mut connection = match &captures["arrow"] {
+            "->" => format!("{from} to {to}"),
+            "<-" => format!("{to} to {from}"),
+            "<->" => format!("{from} to and from {to}"),
+            _ => format!("{from} with {to}"),
+        };
+        if !text.is_empty() {
+            connection.push_str(&format!(" ({text})"));
+        }
+        connections.push(connection);
+    }
+    if shapes.is_empty() {
+        return None;
+    }
+    let mut outline = format!("Shapes: {}.", shapes.join(", "));
+    if !connections.is_empty() {
+        outline.push_str(&format!(" Connections: {}.", connections.join("; ")));
+    }
+    Some(outline)
+}
+
+pub struct DiagramLabels {
+    title: Option<String>,
+    description: Option<String>,
+}
+
+impl DiagramLabels {
+    pub fn new(title: Option<String>, description: Option<String>) -> Self {
+        Self { title, description }
+    }
+
+    pub fn from_arguments(args: &mut Arguments) -> Self {
+        Self {
+            title: args.optional("title"),
+            description: args.optional("description"),
+        }
+    }
+
+    pub fn default_title(&mut self, title: &str) {
+        self.title.get_or_insert_with(|| title.to_string());
+    }
+
+    pub fn apply<F>(&self, root: &mut Element, outline: F)
+    where
+        F: FnOnce(&Element) -> Option<String>,
+    {
+        let description = self.description.clone().or_else(|| outline(root));
+        root.attributes
+            .insert(String::from("role"), String::from("img"));
+        let mut labels = Vec::new();
+        for (name, text) in [
+            ("title", self.title.as_deref()),
+            ("desc", description.as_deref()),
+        ] {
+            if let Some(text) = text.filter(|text| !text.is_empty()) {
+                let mut element = Element::new(name);
+                element.children.push(XMLNode::Text(text.to_string()));
+                labels.push(XMLNode::Element(element));
+            }
+        }
+        root.children.splice(0..0, labels);
+    }
+}
+
+pub fn header_arguments(source: &str, prefix: &str) -> Result<Arguments> {
+    let header = source
+        .lines()
+        .map_while(|line| line.trim().strip_prefix(prefix))
+        .collect::<Vec<_>>()
+        .join(" ");
+    Arguments::parse(&header).wrap_err("in options header")
+}
+
+pub struct Palette {
+    pub fill: &'static str,
+    pub stroke: &'static str,
+    pub text: &'static str,
+    pub background: &'static str,
+    pub grid: &'static str,
+    pub series: [&'static str; 3],
+}
+
+impl Palette {
+    fn new(scheme: Scheme) -> Self {
+        let colour = |name: &str| {
+            property(name, scheme).unwrap_or_else(|| panic!("{name} is not declared in base.css"))
+        };
+        Self {
+            fill: colour("--diagram-color-b"),
+            stroke: colour("--diagram-color-a"),
+            text: colour("--diagram-text"),
+            background: colour("--backgroundcolor"),
+            grid: colour("--diagram-grid"),
+            series: [
+                colour("--diagram-color-a"),
+                colour("--highlight-fill"),
+                colour("--bordercolor"),
+            ],
+        }
+    }
+}
+
+static LIGHT_PALETTE: LazyLock<Palette> = LazyLock::new(|| Palette::new(Scheme::Light));
+static DARK_PALETTE: LazyLock<Palette> = LazyLock::new(|| Palette::new(Scheme::Dark));
+
+pub fn themed_style<F>(rules: F) -> Element
+where
+    F: Fn(&Palette) -> String,
+{
+    let mut style = Element::new("style");
+    style.children.push(XMLNode::Text(format!(
+        "{}@media screen and (prefers-color-scheme:dark){{{}}}",
+        rules(&LIGHT_PALETTE),
+        rules(&DARK_PALETTE)
+    )));
+    style
+}
+
+// labels are measured before the font is known, so this is a generous average advance per em
+pub fn text_width(text: &str, font_size: f64) -> f64 {
+    text.chars().count() as f64 * font_size * 0.6
+}
+
+pub fn element(name: &str, attributes: &[(&str, String)]) -> Element {
+    let mut element = Element::new(name);
+    for (key, value) in attributes {
+        element
+            .attributes
+            .insert(key.to_string(), value.to_string());
+    }
+    element
+}
+
+pub fn text(x: f64, y: f64, class: &str, anchor: &str, content: &str) -> Element {
+    let mut text = element(
+        "text",
+        &[
+            ("x", x.to_string()),
+            ("y", y.to_string()),
+            ("class", class.to_string()),
+            ("text-anchor", anchor.to_string()),
+            ("dominant-baseline", String::from("middle")),
+        ],
+    );
+    text.children.push(XMLNode::Text(content.to_string()));
+    text
+}
+
+pub fn path(class: &str, points: &[(f64, f64)], closed: bool) -> Element {
+    let mut d = String::new();
+    for (index, (x, y)) in points.iter().enumerate() {
+        d.push_str(&format!("{}{x},{y} ", if index == 0 { 'M' } else { 'L' }));
+    }
+    if closed {
+        d.push('Z');
+    }
+    element(
+        "path",
+        &[
+            ("class", class.to_string()),
+            ("d", d.trim_end().to_string()),
+        ],
+    )
+}
+
+static D2_DIAGNOSTIC: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:^|\s)[^\s:]+:(?P<line>\d+):(?P<column>\d+): (?P<message>.+)$")
+        .expect("compile time regex invalid")
+});
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    line: usize,
+    column: usize,
+    message: String,
+    in_preamble: bool,
+}
+
+#[derive(Debug)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn single(line: usize, column: usize, message: String) -> Self {
+        Self(vec![Diagnostic {
+            line,
+            column,
+            message,
+            in_preamble: false,
+        }])
+    }
+
+    pub fn offset(mut self, lines: usize) -> Self {
+        for diagnostic in &mut self.0 {
+            if !diagnostic.in_preamble {
+                diagnostic.line += lines;
+            }
+        }
+        self
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, diagnostic) in self.0.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            if diagnostic.in_preamble {
+                write!(f, "preamble.d2 ")?;
+            }
+            write!(
+                f,
+                "line {}, column {}: {}",
+                diagnostic.line, diagnostic.column, diagnostic.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+const D2_THEMES: [u32; 17] = [
+    0, 1, 3, 4, 5, 6, 7, 8, 100, 101, 102, 103, 104, 105, 200, 300, 301,
+];
+
+const D2_OPTIONS_HEADER: &str = "# d2-options:";
+
+pub struct D2Options {
+    layout: String,
+    theme: u32,
+    dark_theme: u32,
+    sketch: bool,
+    pad: u32,
+    scale: Option<f32>,
+    preamble: bool,
+    animate_interval: Option<u32>,
+    stepper: bool,
+    labels: DiagramLabels,
+}
+
+pub struct Board {
+    pub name: String,
+    pub svg: String,
+}
+
+impl D2Options {
+    pub fn from_arguments(args: &mut Arguments) -> Result<Self> {
+        let layout = args
+            .optional("layout")
+            .unwrap_or_else(|| String::from("dagre"));
+        if !matches!(layout.as_str(), "dagre" | "elk") {
+            bail!("{layout} is not a d2 layout engine; expected dagre or elk");
+        }
+        let theme = args.parsed("theme", 1)?;
+        let dark_theme = args.parsed("dark-theme", theme)?;
+        for theme in [theme, dark_theme] {
+            if !D2_THEMES.contains(&theme) {
+                bail!("{theme} is not a d2 theme id");
+            }
+        }
+        let scale = args
+            .optional("scale")
+            .map(|scale| scale.parse::<f32>())
+            .transpose()?;
+        if let Some(scale) = scale
+            && (scale.is_nan() || scale <= 0.0)
+        {
+            bail!("scale {scale} must be positive");
+        }
+        let animate_interval = args
+            .optional("animate-interval")
+            .map(|interval| interval.parse::<u32>())
+            .transpose()?;
+        let stepper = args.parsed("stepper", false)?;
+        if animate_interval.is_some() && stepper {
+            bail!("a diagram may be animated or shown in a stepper, but not both");
+        }
+        if animate_interval == Some(0) {
+            bail!("animate-interval must be at least one millisecond");
+        }
+        Ok(Self {
+            layout,
+            theme,
+            dark_theme,
+            sketch: args.parsed("sketch", false)?,
+            pad: args.parsed("pad", 0)?,
+            scale,
+            preamble: args.parsed("preamble", true)?,
+            animate_interval,
+            stepper,
+            labels: DiagramLabels::from_arguments(args),
+        })
+    }
+
+    pub fn from_header(source: &str) -> Result<Self> {
+        let mut args = header_arguments(source, D2_OPTIONS_HEADER)?;
+        let options = Self::from_arguments(&mut args)?;
+        args.finish()?;
+        Ok(options)
+    }
+
+    pub fn default_title(&mut self, title: &str) {
+        self.labels.default_title(title);
+    }
+
+    pub fn stepper(&self) -> bool {
+        self.stepper
+    }
+
+    fn preamble(&self) -> &'static [u8] {
+        if self.preamble {
+            include_bytes!("preamble.d2")
+        } else {
+            b""
+        }
+    }
+
+    fn arguments(&self, config: &SiteConfiguration) -> Vec<OsString> {
+        let mut arguments = vec![
+            OsString::from(format!("--layout={}", self.layout)),
+            OsString::from(format!("--theme={}", self.theme)),
+            OsString::from(format!("--dark-theme={}", self.dark_theme)),
+            OsString::from_iter([
+                OsStr::new("--font-regular="),
+                config.get_fontbook().regular.as_os_str(),
+            ]),
+            OsString::from_iter([
+                OsStr::new("--font-italic="),
+                config.get_fontbook().italic.as_os_str(),
+            ]),
+            OsString::from_iter([
+                OsStr::new("--font-bold="),
+                config.get_fontbook().bold.as_os_str(),
+            ]),
+            OsString::from_iter([
+                OsStr::new("--font-semibold="),
+                config.get_fontbook().semibold.as_os_str(),
+            ]),
+            OsString::from(format!("--pad={}", self.pad)),
+        ];
+        if self.sketch {
+            arguments.push(OsString::from("--sketch"));
+        }
+        if let Some(scale) = self.scale {
+            arguments.push(OsString::from(format!("--scale={scale}")));
+        }
+        if let Some(interval) = self.animate_interval {
+            arguments.push(OsString::from(format!("--animate-interval={interval}")));
+        }
+        arguments
+    }
+}
+
+// positions are reported against the preamble and source together, so they are moved back into one
+fn d2_diagnostics(stderr: &str, preamble: &[u8]) -> Result<Vec<Diagnostic>> {
+    let preamble_lines = preamble.iter().filter(|byte| **byte == b'\n').count();
+    let mut diagnostics = Vec::new();
+    for line in stderr.lines() {
+        if let Some(captures) = D2_DIAGNOSTIC.captures(line) {
+            let line = captures["line"].parse::<usize>()?;
+            let in_preamble = line <= preamble_lines;
+            diagnostics.push(Diagnostic {
+                line: if in_preamble {
+                    line
+                } else {
+                    line - preamble_lines
+                },
+                column: captures["column"].parse()?,
+                message: captures["message"].to_string(),
+                in_preamble,
+            });
+            continue;
+        }
+        match line.split_once(":") {
+            Some(("info", msg)) => info!("{}", msg.trim()),
+            Some(("err", msg)) => error!("{}", msg.trim()),
+            Some((_, _)) | None => info!("{}", line.trim()),
+        }
+    }
+    Ok(diagnostics)
+}
+
+fn run_d2(
+    executable: &Path,
+    arguments: &[OsString],
+    preamble: &[u8],
+    source: &[u8],
+    output: Option<&Path>,
+) -> Result<Vec<u8>> {
+    let mut d2 = Command::new(executable)
+        .args(arguments)
         .arg("-")
+        .args(output)
         .stdin(Stdio::piped())
         .stderr(Stdio::piped())
         .stdout(Stdio::piped())
@@ -92,22 +541,336 @@ pub fn compile_d2(config: &SiteConfiguration, source: &[u8]) -> Result<String> {
             .stdin
             .take()
             .ok_or_eyre("d2 closed stdin before we could open it")?;
-        stdin.write_all(include_bytes!("preamble.d2"))?;
+        stdin.write_all(preamble)?;
         stdin.write_all(source)?;
     }
     let output = d2.wait_with_output()?;
-    for line in String::from_utf8_lossy(&output.stderr).lines() {
-        match line.split_once(":") {
-            Some(("info", msg)) => info!("{}", msg.trim()),
-            Some(("err", msg)) => error!("{}", msg.trim()),
-            Some((_, _)) | None => info!("{}", line.trim()),
-        }
-    }
+    let diagnostics = d2_diagnostics(&String::from_utf8_lossy(&output.stderr), preamble)?;
     if output.status.success() {
-        let mut destination = Vec::new();
-        postprocess_svg_css(Cursor::new(output.stdout), &mut destination)?;
-        Ok(String::from_utf8(destination)?)
-    } else {
+        Ok(output.stdout)
+    } else if diagnostics.is_empty() {
         Err(eyre!("d2 exit status indicated failure"))
+    } else {
+        Err(Diagnostics(diagnostics).into())
+    }
+}
+
+fn cache_key(
+    config: &SiteConfiguration,
+    arguments: &[OsString],
+    preamble: &[u8],
+    source: &[u8],
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(source);
+    hasher.update(preamble);
+    // font paths differ between machines; the fonts hash below covers their contents
+    for argument in arguments
+        .iter()
+        .filter(|argument| !argument.as_encoded_bytes().starts_with(b"--font-"))
+    {
+        hasher.update(argument.as_encoded_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(config.get_d2_fonts_hash()?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn read_cache(path: &Path) -> Result<Option<(String, Vec<u8>)>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let cached = std::fs::read(path)?;
+    let (header, svg) = cached
+        .split_at_checked(
+            cached
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .ok_or_eyre("d2 cache entry is missing its header")?
+                + 1,
+        )
+        .ok_or_eyre("d2 cache entry is missing its header")?;
+    let version = String::from_utf8_lossy(header)
+        .trim()
+        .strip_prefix("<!-- d2 ")
+        .and_then(|header| header.strip_suffix(" -->"))
+        .ok_or_eyre("d2 cache entry has a malformed header")?
+        .to_string();
+    Ok(Some((version, svg.to_vec())))
+}
+
+fn board_order(name: &str) -> Vec<(u8, u64, String)> {
+    // d2 names board files after their keys, so numbered steps are sorted numerically
+    name.split('/')
+        .map(|component| match component.parse() {
+            Ok(number) => (0, number, String::new()),
+            Err(_) => (1, 0, component.to_string()),
+        })
+        .collect()
+}
+
+fn collect_boards(output: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    if output.is_file() {
+        return Ok(vec![(String::new(), std::fs::read(output)?)]);
+    }
+    let root = output.with_extension("");
+    let mut boards = Vec::new();
+    for entry in WalkDir::new(&root) {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type().is_file() && path.extension().is_some_and(|ext| ext == "svg") {
+            let name = path.strip_prefix(&root)?.with_extension("");
+            let name = name.to_string_lossy().replace('\\', "/");
+            let name = match name.strip_suffix("index") {
+                Some(parent) => parent.trim_end_matches('/').to_string(),
+                None => name,
+            };
+            boards.push((name, std::fs::read(path)?));
+        }
+    }
+    if boards.is_empty() {
+        bail!("d2 produced no boards");
+    }
+    boards.sort_by_cached_key(|(name, _)| board_order(name));
+    Ok(boards)
+}
+
+fn encode_boards(boards: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for (name, svg) in boards {
+        encoded.extend_from_slice(format!("{name}\n{}\n", svg.len()).as_bytes());
+        encoded.extend_from_slice(svg);
+    }
+    encoded
+}
+
+fn decode_boards(mut encoded: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut boards = Vec::new();
+    while !encoded.is_empty() {
+        let mut header = encoded.splitn(3, |byte| *byte == b'\n');
+        let (Some(name), Some(length), Some(rest)) = (header.next(), header.next(), header.next())
+        else {
+            bail!("d2 board list is truncated");
+        };
+        let length = String::from_utf8_lossy(length).parse()?;
+        let (svg, rest) = rest
+            .split_at_checked(length)
+            .ok_or_eyre("d2 board list is truncated")?;
+        boards.push((String::from_utf8(name.to_vec())?, svg.to_vec()));
+        encoded = rest;
+    }
+    Ok(boards)
+}
+
+fn run_d2_boards(
+    executable: &Path,
+    arguments: &[OsString],
+    preamble: &[u8],
+    source: &[u8],
+) -> Result<Vec<u8>> {
+    let directory = tempfile::tempdir()?;
+    let output = directory.path().join("board.svg");
+    run_d2(executable, arguments, preamble, source, Some(&output))?;
+    Ok(encode_boards(&collect_boards(&output)?))
+}
+
+fn compile_d2_output<F>(
+    config: &SiteConfiguration,
+    arguments: &[OsString],
+    preamble: &[u8],
+    source: &[u8],
+    extension: &str,
+    run: F,
+) -> Result<Vec<u8>>
+where
+    F: FnOnce(&Path) -> Result<Vec<u8>>,
+{
+    let Some(cache) = config.get_d2_cache() else {
+        let (executable, _) = config
+            .get_d2()
+            .ok_or_eyre("d2 is unavailable and no diagram cache was configured")?;
+        return run(executable);
+    };
+    let path = cache.join(format!(
+        "{}.{extension}",
+        cache_key(config, arguments, preamble, source)?
+    ));
+    config.use_d2_cache_entry(&path);
+    let cached = read_cache(&path)?;
+    match (config.get_d2(), cached) {
+        (Some((_, version)), Some((cached_version, output))) if *version == cached_version => {
+            info!("using cached d2 output");
+            Ok(output)
+        }
+        (Some((executable, version)), _) => {
+            let output = run(executable)?;
+            create_dir_all(cache)?;
+            let mut entry = format!("<!-- d2 {version} -->\n").into_bytes();
+            entry.extend_from_slice(&output);
+            std::fs::write(&path, entry)?;
+            Ok(output)
+        }
+        (None, Some((cached_version, output))) => {
+            warn!("d2 unavailable; using output cached from d2 {cached_version}");
+            Ok(output)
+        }
+        (None, None) => Err(eyre!(
+            "d2 is unavailable and this diagram has no cached output"
+        )),
+    }
+}
+
+// entries are keyed on their inputs, so any edit leaves the old entry behind; once every diagram has
+// been built, those no diagram asked for are removed. other files in the directory are left alone
+pub fn prune_d2_cache(config: &SiteConfiguration) -> Result<()> {
+    let Some(cache) = config.get_d2_cache().filter(|cache| cache.is_dir()) else {
+        return Ok(());
+    };
+    for entry in std::fs::read_dir(cache)? {
+        let path = entry?.path();
+        let is_entry = path.file_stem().is_some_and(|stem| {
+            stem.len() == 64 && stem.as_encoded_bytes().iter().all(u8::is_ascii_hexdigit)
+        }) && path
+            .extension()
+            .is_some_and(|extension| extension == "svg" || extension == "boards");
+        if is_entry && path.is_file() && !config.is_d2_cache_entry_used(&path) {
+            info!("removing unused d2 cache entry {}", path.to_string_lossy());
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+fn postprocess_svg(svg: Vec<u8>, options: &D2Options) -> Result<String> {
+    let mut root = Element::parse(Cursor::new(svg))?;
+    postprocess_svg_css(&mut root)?;
+    options.labels.apply(&mut root, d2_outline);
+    let mut destination = Vec::new();
+    root.write(&mut destination)?;
+    Ok(String::from_utf8(destination)?)
+}
+
+pub fn compile_d2(
+    config: &SiteConfiguration,
+    source: &[u8],
+    options: &D2Options,
+) -> Result<String> {
+    let arguments = options.arguments(config);
+    let preamble = options.preamble();
+    postprocess_svg(
+        compile_d2_output(config, &arguments, preamble, source, "svg", |executable| {
+            run_d2(executable, &arguments, preamble, source, None)
+        })?,
+        options,
+    )
+}
+
+pub fn compile_d2_boards(
+    config: &SiteConfiguration,
+    source: &[u8],
+    options: &D2Options,
+) -> Result<Vec<Board>> {
+    if !options.stepper {
+        return Ok(vec![Board {
+            name: String::new(),
+            svg: compile_d2(config, source, options)?,
+        }]);
+    }
+    let arguments = options.arguments(config);
+    let preamble = options.preamble();
+    let boards = compile_d2_output(
+        config,
+        &arguments,
+        preamble,
+        source,
+        "boards",
+        |executable| run_d2_boards(executable, &arguments, preamble, source),
+    )?;
+    decode_boards(&boards)?
+        .into_iter()
+        .map(|(name, svg)| {
+            Ok(Board {
+                name,
+                svg: postprocess_svg(svg, options)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preamble_matches_base_css() -> Result<()> {
+        let preamble = include_str!("preamble.d2");
+        let (dark, light) = preamble
+            .split_once("\n    theme-overrides:")
+            .ok_or_eyre("preamble.d2 has light overrides")?;
+        for (overrides, scheme) in [(dark, Scheme::Dark), (light, Scheme::Light)] {
+            for line in overrides.lines() {
+                let Some((value, name)) = line.split_once(" # ") else {
+                    continue;
+                };
+                let value = value
+                    .rsplit(':')
+                    .next()
+                    .map(|value| value.trim().trim_matches('"').to_lowercase());
+                assert_eq!(value.as_deref(), property(name, scheme), "{line}");
+            }
+        }
+        for palette in [&*LIGHT_PALETTE, &*DARK_PALETTE] {
+            assert!(palette.fill.starts_with('#'));
+            assert_ne!(palette.fill, palette.stroke);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn diagnostics_leave_the_preamble() -> Result<()> {
+        let preamble = b"vars: {\n  x: 1\n}\n";
+        let diagnostics = d2_diagnostics(
+            "err: -:5:3: unexpected text\nerr: -:2:1: bad variable\ninfo: compiled\n",
+            preamble,
+        )?;
+        assert_eq!(
+            Diagnostics(diagnostics).offset(10).to_string(),
+            "line 12, column 3: unexpected text\npreamble.d2 line 2, column 1: bad variable"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn boards_round_trip() -> Result<()> {
+        let boards = vec![
+            (String::new(), b"<svg>\nroot\n</svg>".to_vec()),
+            (String::from("steps/1"), b"<svg/>".to_vec()),
+            (String::from("steps/2"), Vec::new()),
+        ];
+        assert_eq!(decode_boards(&encode_boards(&boards))?, boards);
+        assert!(decode_boards(b"")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_boards() {
+        let encoded = encode_boards(&[(String::from("a"), b"<svg/>".to_vec())]);
+        for length in 1..encoded.len() {
+            assert!(
+                decode_boards(&encoded[..length]).is_err(),
+                "cut at {length}"
+            );
+        }
+        assert!(decode_boards(b"a\nlong\n<svg/>").is_err());
+    }
+
+    #[test]
+    fn numbered_boards_sort_numerically() {
+        let mut names = ["steps/10", "", "steps/2", "layers/b", "steps/1", "layers/a"];
+        names.sort_by_cached_key(|name| board_order(name));
+        assert_eq!(
+            names,
+            ["", "layers/a", "layers/b", "steps/1", "steps/2", "steps/10"]
+        );
     }
 }
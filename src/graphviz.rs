@@ -0,0 +1,215 @@
+use std::io::Cursor;
+
+use color_eyre::eyre::{Result, bail, eyre};
+use layout::{
+    backends::svg::SVGWriter,
+    gv::{
+        DotParser, GraphBuilder,
+        parser::ast::{ArrowKind, AttributeList, Graph, Stmt},
+    },
+};
+use xmltree::{Element, XMLNode};
+
+use crate::{
+    content::SiteConfiguration,
+    diagrams::{DiagramLabels, header_arguments, themed_style},
+    shortcodes::Arguments,
+};
+
+const DOT_OPTIONS_HEADER: &str = "// dot-options:";
+
+// layout-rs draws everything that is not explicitly styled in black on white
+const DEFAULT_STROKE: &str = "#000000ff";
+const DEFAULT_FILL: &str = "#ffffffff";
+
+pub struct DotOptions {
+    labels: DiagramLabels,
+}
+
+impl DotOptions {
+    pub fn from_arguments(args: &mut Arguments) -> Self {
+        Self {
+            labels: DiagramLabels::from_arguments(args),
+        }
+    }
+
+    pub fn from_header(source: &str) -> Result<Self> {
+        let mut args = header_arguments(source, DOT_OPTIONS_HEADER)?;
+        let options = Self::from_arguments(&mut args);
+        args.finish()?;
+        Ok(options)
+    }
+
+    pub fn default_title(&mut self, title: &str) {
+        self.labels.default_title(title);
+    }
+}
+
+fn label(list: &AttributeList) -> Option<&str> {
+    list.iter()
+        .find(|(key, _)| key == "label")
+        .map(|(_, value)| value.as_str())
+}
+
+fn add_shape(shapes: &mut Vec<(String, String)>, id: &str, label: Option<&str>) {
+    match shapes.iter_mut().find(|(existing, _)| existing == id) {
+        Some((_, existing)) => {
+            if let Some(label) = label {
+                *existing = label.to_string();
+            }
+        }
+        None => shapes.push((id.to_string(), label.unwrap_or(id).to_string())),
+    }
+}
+
+struct Connection<'a> {
+    from: &'a str,
+    to: &'a str,
+    directed: bool,
+    label: Option<&'a str>,
+}
+
+fn collect_statements<'a>(
+    graph: &'a Graph,
+    shapes: &mut Vec<(String, String)>,
+    connections: &mut Vec<Connection<'a>>,
+) {
+    for statement in &graph.list.list {
+        match statement {
+            Stmt::Node(node) => add_shape(shapes, &node.id.name, label(&node.list)),
+            Stmt::Edge(edge) => {
+                add_shape(shapes, &edge.from.name, None);
+                let mut from = edge.from.name.as_str();
+                for (to, arrow) in &edge.to {
+                    add_shape(shapes, &to.name, None);
+                    connections.push(Connection {
+                        from,
+                        to: &to.name,
+                        directed: matches!(arrow, ArrowKind::Arrow),
+                        label: label(&edge.list),
+                    });
+                    from = &to.name;
+                }
+            }
+            Stmt::SubGraph(graph) => collect_statements(graph, shapes, connections),
+            Stmt::Attribute(_) => {}
+        }
+    }
+}
+
+fn dot_outline(graph: &Graph) -> Option<String> {
+    let mut shapes = Vec::new();
+    let mut connections = Vec::new();
+    collect_statements(graph, &mut shapes, &mut connections);
+    if shapes.is_empty() {
+        return None;
+    }
+    let shape_label = |id: &str| {
+        shapes
+            .iter()
+            .find(|(shape, _)| shape == id)
+            .map_or(id, |(_, label)| label.as_str())
+            .to_string()
+    };
+    let connections = connections
+        .iter()
+        .map(|connection| {
+            let (from, to) = (shape_label(connection.from), shape_label(connection.to));
+            let mut description = if connection.directed {
+                format!("{from} to {to}")
+            } else {
+                format!("{from} with {to}")
+            };
+            if let Some(label) = connection.label {
+                description.push_str(&format!(" ({label})"));
+            }
+            description
+        })
+        .collect::<Vec<_>>();
+    let mut outline = format!(
+        "Shapes: {}.",
+        shapes
+            .iter()
+            .map(|(_, label)| label.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    if !connections.is_empty() {
+        outline.push_str(&format!(" Connections: {}.", connections.join("; ")));
+    }
+    Some(outline)
+}
+
+fn restyle(element: &mut Element, family: &str) {
+    if element.matches("style") {
+        let css = element.get_text().unwrap_or_default().replace(
+            "font-family: Times, serif;",
+            &format!("font-family: \"{family}\", sans-serif;"),
+        );
+        element.children = vec![XMLNode::Text(css)];
+        return;
+    }
+    let mut classes = Vec::new();
+    if element
+        .attributes
+        .get("stroke")
+        .is_some_and(|stroke| stroke == DEFAULT_STROKE)
+    {
+        element.attributes.remove("stroke");
+        classes.push("stroke");
+    }
+    if element
+        .attributes
+        .get("fill")
+        .is_some_and(|fill| fill == DEFAULT_FILL)
+    {
+        element.attributes.remove("fill");
+        classes.push("fill");
+    }
+    if !classes.is_empty() {
+        let class = element.attributes.entry(String::from("class")).or_default();
+        for name in classes {
+            if !class.is_empty() {
+                class.push(' ');
+            }
+            class.push_str(name);
+        }
+    }
+    for child in &mut element.children {
+        if let XMLNode::Element(child) = child {
+            restyle(child, family);
+        }
+    }
+}
+
+pub fn compile_dot(
+    config: &SiteConfiguration,
+    source: &str,
+    options: &DotOptions,
+) -> Result<String> {
+    let mut parser = DotParser::new(source);
+    let graph = parser
+        .process()
+        .map_err(|err| eyre!("could not parse DOT: {err}"))?;
+    let mut builder = GraphBuilder::new();
+    builder.visit_graph(&graph);
+    let mut visual = builder.get();
+    if visual.num_nodes() == 0 {
+        bail!("graph has no nodes");
+    }
+    let mut writer = SVGWriter::new();
+    visual.do_it(false, false, false, &mut writer);
+
+    let mut root = Element::parse(Cursor::new(writer.finalize()))?;
+    restyle(&mut root, &config.get_fontbook().regular_family()?);
+    root.children.push(XMLNode::Element(themed_style(|palette| {
+        format!(
+            "text{{fill:{}}}.fill{{fill:{}}}.stroke{{stroke:{}}}marker polygon{{fill:{}}}",
+            palette.text, palette.fill, palette.stroke, palette.stroke
+        )
+    })));
+    options.labels.apply(&mut root, |_| dot_outline(&graph));
+    let mut destination = Vec::new();
+    root.write(&mut destination)?;
+    Ok(String::from_utf8(destination)?)
+}
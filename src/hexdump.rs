@@ -0,0 +1,408 @@
+use color_eyre::eyre::{Result, bail, eyre};
+
+use crate::{
+    content::{ContentReference, SiteConfiguration},
+    diagrams::Diagnostics,
+    shortcodes::Arguments,
+    util::escape_html,
+};
+
+// scope names shared with the classes syntect emits, so themes style both alike
+const SCOPES: [(&str, &str); 6] = [
+    ("keyword", "keyword operator"),
+    ("storage", "storage type"),
+    ("string", "string"),
+    ("constant", "constant numeric"),
+    ("comment", "comment"),
+    ("entity", "entity name"),
+];
+
+struct Highlight {
+    start: u64,
+    end: u64,
+    scope: &'static str,
+    label: String,
+}
+
+fn parse_address(value: &str) -> Result<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| eyre!("{value} is not a decimal or 0x-prefixed hex address"))
+}
+
+fn parse_highlight(line: &str) -> Result<Highlight> {
+    let mut parts = line.splitn(3, char::is_whitespace);
+    let range = parts.next().unwrap_or_default();
+    let colour = parts
+        .next()
+        .ok_or_else(|| eyre!("highlight needs a range, a colour and a label"))?;
+    let label = parts.next().unwrap_or_default().trim().to_string();
+    let (start, end) = if let Some((start, length)) = range.split_once('+') {
+        let start = parse_address(start)?;
+        match parse_address(length)? {
+            0 => bail!("highlight {range} is empty"),
+            length => (start, start + length),
+        }
+    } else if let Some((start, last)) = range.split_once('-') {
+        (parse_address(start)?, parse_address(last)? + 1)
+    } else {
+        let start = parse_address(range)?;
+        (start, start + 1)
+    };
+    if end <= start {
+        bail!("highlight {range} ends before it starts");
+    }
+    let scope = SCOPES
+        .iter()
+        .find_map(|(name, scope)| (*name == colour).then_some(*scope))
+        .ok_or_else(|| {
+            eyre!(
+                "{colour} is not a highlight colour; expected one of {}",
+                SCOPES.map(|(name, _)| name).join(", ")
+            )
+        })?;
+    Ok(Highlight {
+        start,
+        end,
+        scope,
+        label,
+    })
+}
+
+fn parse_hex(line: &str, data: &mut Vec<u8>) -> Result<()> {
+    let digits = line
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<_>>();
+    for pair in digits.chunks(2) {
+        let [high, low] = pair else {
+            bail!("odd number of hex digits");
+        };
+        match (high.to_digit(16), low.to_digit(16)) {
+            (Some(high), Some(low)) => data.push((high << 4 | low) as u8),
+            _ => bail!("{high}{low} is not a hex byte"),
+        }
+    }
+    Ok(())
+}
+
+fn read_source(
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+    args: &mut Arguments,
+    source: &str,
+) -> Result<(u64, Vec<u8>, Vec<Highlight>)> {
+    let src = args.optional("src");
+    let offset = args
+        .optional("offset")
+        .map(|offset| parse_address(&offset))
+        .transpose()?
+        .unwrap_or(0);
+    let length = args
+        .optional("length")
+        .map(|length| parse_address(&length))
+        .transpose()?;
+
+    let mut data = Vec::new();
+    let mut highlights = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim();
+        let result = if line.is_empty() || line.starts_with('#') {
+            Ok(())
+        } else if let Some(highlight) = line.strip_prefix("highlight ") {
+            parse_highlight(highlight.trim()).map(|highlight| highlights.push(highlight))
+        } else if src.is_some() {
+            Err(eyre!("hex data cannot be combined with src"))
+        } else {
+            parse_hex(line, &mut data)
+        };
+        result.map_err(|err| Diagnostics::single(index + 1, 1, err.to_string()))?;
+    }
+
+    if let Some(src) = src {
+        let path = content_reference.resolve_relative_content_path(config, &src);
+        let file = std::fs::read(&path)
+            .map_err(|err| eyre!("error opening {}: {err}", path.to_string_lossy()))?;
+        let start = usize::try_from(offset)?;
+        if start > file.len() {
+            bail!("offset {offset:#x} is past the end of {src}");
+        }
+        let end = match length {
+            Some(length) => start
+                .checked_add(usize::try_from(length)?)
+                .filter(|end| *end <= file.len())
+                .ok_or_else(|| eyre!("length {length:#x} runs past the end of {src}"))?,
+            None => file.len(),
+        };
+        data = file[start..end].to_vec();
+    } else if length.is_some() {
+        bail!("length only applies to a src file");
+    }
+    if data.is_empty() {
+        bail!("a hex dump needs some bytes");
+    }
+    Ok((offset, data, highlights))
+}
+
+fn check_highlights(highlights: &mut [Highlight], start: u64, end: u64) -> Result<()> {
+    highlights.sort_by_key(|highlight| highlight.start);
+    for highlight in highlights.iter() {
+        if highlight.start < start || highlight.end > end {
+            bail!(
+                "highlight {:#x}-{:#x} is outside the dumped bytes {start:#x}-{:#x}",
+                highlight.start,
+                highlight.end - 1,
+                end - 1
+            );
+        }
+    }
+    for pair in highlights.windows(2) {
+        if pair[1].start < pair[0].end {
+            bail!(
+                "highlights at {:#x} and {:#x} overlap",
+                pair[0].start,
+                pair[1].start
+            );
+        }
+    }
+    Ok(())
+}
+
+struct Column<'a> {
+    html: String,
+    open: Option<&'a Highlight>,
+}
+
+impl<'a> Column<'a> {
+    fn push(&mut self, highlight: Option<&'a Highlight>, cell: &str) {
+        let same = match (self.open, highlight) {
+            (Some(open), Some(highlight)) => std::ptr::eq(open, highlight),
+            (None, None) => true,
+            _ => false,
+        };
+        if !same {
+            self.close();
+            if let Some(highlight) = highlight {
+                self.html.push_str(&format!(
+                    r#"<mark class="{}" title="{}">"#,
+                    highlight.scope,
+                    escape_html(&highlight.label)
+                ));
+            }
+            self.open = highlight;
+        }
+        self.html.push_str(cell);
+    }
+
+    fn close(&mut self) {
+        if self.open.take().is_some() {
+            self.html.push_str("</mark>");
+        }
+    }
+}
+
+pub fn render_hexdump(
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+    args: &str,
+    source: &str,
+) -> Result<String> {
+    let mut args = Arguments::parse(args)?;
+    let width = args.parsed("width", 16usize)?;
+    if !(1..=64).contains(&width) {
+        bail!("width must be between 1 and 64 bytes");
+    }
+    let caption = args.optional("caption");
+    let (start, data, mut highlights) = read_source(config, content_reference, &mut args, source)?;
+    args.finish()?;
+    let end = start + data.len() as u64;
+    check_highlights(&mut highlights, start, end)?;
+
+    let digits = format!("{:x}", end - 1).len().max(8);
+    let highlight_at = |address: u64| {
+        highlights
+            .iter()
+            .find(|highlight| (highlight.start..highlight.end).contains(&address))
+    };
+    let mut html = String::from(r#"<figure class="hexdump"><pre><code class="source">"#);
+    for (row, bytes) in data.chunks(width).enumerate() {
+        let address = start + (row * width) as u64;
+        html.push_str(&format!(
+            r#"<span class="address">{address:0digits$x}</span>  "#
+        ));
+        let mut hex = Column {
+            html: String::new(),
+            open: None,
+        };
+        let mut ascii = Column {
+            html: String::new(),
+            open: None,
+        };
+        for (column, byte) in bytes.iter().enumerate() {
+            let highlight = highlight_at(address + column as u64);
+            if column > 0 {
+                // keep the separator inside a mark only when the range continues past it
+                let previous = highlight_at(address + column as u64 - 1);
+                let separator = if column % 8 == 0 { "  " } else { " " };
+                match (previous, highlight) {
+                    (Some(previous), Some(highlight)) if std::ptr::eq(previous, highlight) => {
+                        hex.push(Some(highlight), separator)
+                    }
+                    _ => {
+                        hex.close();
+                        hex.html.push_str(separator);
+                    }
+                }
+            }
+            hex.push(highlight, &format!("{byte:02x}"));
+            let character = match *byte {
+                byte @ 0x20..=0x7e => escape_html(&char::from(byte).to_string()),
+                _ => String::from("."),
+            };
+            ascii.push(highlight, &character);
+        }
+        hex.close();
+        ascii.close();
+        let missing = width - bytes.len();
+        let padding = missing * 3 + (width - 1) / 8 - (bytes.len() - 1) / 8;
+        html.push_str(&hex.html);
+        html.push_str(&" ".repeat(padding));
+        html.push_str(&format!("  |{}|\n", ascii.html));
+    }
+    html.push_str("</code></pre>");
+
+    let labelled = highlights
+        .iter()
+        .filter(|highlight| !highlight.label.is_empty())
+        .collect::<Vec<_>>();
+    if !labelled.is_empty() || caption.is_some() {
+        html.push_str("<figcaption>");
+        if let Some(caption) = &caption {
+            html.push_str(&format!("<p>{}</p>", escape_html(caption)));
+        }
+        if !labelled.is_empty() {
+            html.push_str(r#"<ul class="source">"#);
+            for highlight in labelled {
+                let range = if highlight.end - highlight.start == 1 {
+                    format!("{:#x}", highlight.start)
+                } else {
+                    format!("{:#x}–{:#x}", highlight.start, highlight.end - 1)
+                };
+                html.push_str(&format!(
+                    r#"<li><mark class="{}">{range}</mark> {}</li>"#,
+                    highlight.scope,
+                    escape_html(&highlight.label)
+                ));
+            }
+            html.push_str("</ul>");
+        }
+        html.push_str("</figcaption>");
+    }
+    html.push_str("</figure>");
+    Ok(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use color_eyre::eyre::OptionExt;
+    use regex::Regex;
+
+    use crate::content::test_configuration;
+
+    use super::*;
+
+    fn hexdump(args: &str, source: &str) -> Result<String> {
+        let config = test_configuration();
+        let content_reference = ContentReference::new(&config, PathBuf::from("content/post.md"))?;
+        render_hexdump(&config, &content_reference, args, source)
+    }
+
+    fn rows(html: &str) -> Result<Vec<String>> {
+        let tags = Regex::new(r"<[^>]+>")?;
+        let code = html
+            .split_once("<code class=\"source\">")
+            .and_then(|(_, code)| code.split_once("</code>"))
+            .map(|(code, _)| code)
+            .ok_or_eyre("hexdump has a code block")?;
+        Ok(code
+            .lines()
+            .map(|line| tags.replace_all(line, "").into_owned())
+            .collect())
+    }
+
+    #[test]
+    fn short_rows_are_padded() -> Result<()> {
+        let rows = rows(&hexdump(
+            "",
+            "48656c6c6f2c20776f726c6421000102\n0304050607",
+        )?)?;
+        assert_eq!(
+            rows,
+            [
+                "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 00 01 02  |Hello, world!...|",
+                "00000010  03 04 05 06 07                                    |.....|",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn padding_follows_the_width() -> Result<()> {
+        for (width, bytes) in [(8, 3), (8, 8), (12, 9), (16, 1), (16, 8), (16, 9), (24, 17)] {
+            let source = "ab".repeat(width + bytes);
+            let rows = rows(&hexdump(&format!(r#"width="{width}""#), &source)?)?;
+            let bars = rows.iter().map(|row| row.find('|')).collect::<Vec<_>>();
+            assert_eq!(bars[0], bars[1], "width {width} with {bytes} bytes left");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn highlights_span_separators_inside_their_range() -> Result<()> {
+        let html = hexdump(
+            "",
+            "000102030405060708090a0b0c0d0e0f1011\nhighlight 0x6+4 string length\nhighlight 0xf-0x10 constant tag",
+        )?;
+        assert!(html.contains(r#"05 <mark class="string" title="length">06 07  08 09</mark> 0a"#));
+        // a range across rows is closed at the end of one and reopened on the next
+        assert!(html.contains(r#"<mark class="constant numeric" title="tag">0f</mark>"#));
+        assert!(html.contains(r#"  <mark class="constant numeric" title="tag">10</mark> 11"#));
+        assert!(html.contains(r#"<li><mark class="string">0x6–0x9</mark> length</li>"#));
+        Ok(())
+    }
+
+    #[test]
+    fn offsets_number_the_rows() -> Result<()> {
+        let rows = rows(&hexdump(r#"offset="0x1234" width="4""#, "3c3e2622 41")?)?;
+        assert_eq!(
+            rows,
+            [
+                "00001234  3c 3e 26 22  |&lt;&gt;&amp;&quot;|",
+                "00001238  41           |A|"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_highlights() -> Result<()> {
+        for source in [
+            "0001\nhighlight 0+0 string",
+            "0001\nhighlight 1-0 string",
+            "0001\nhighlight 0 purple",
+            "0001\nhighlight 0",
+            "0001\nhighlight 1+2 string",
+            "000102\nhighlight 0-1 string\nhighlight 1 comment",
+        ] {
+            assert!(hexdump("", source).is_err(), "{source}");
+        }
+        for source in ["", "0", "zz", "00\n0"] {
+            assert!(hexdump("", source).is_err(), "{source}");
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,46 @@
 #![forbid(unsafe_code)]
 #![deny(clippy::unwrap_used)]
 
+mod ansi;
+mod attachments;
+mod bytefield;
+mod cast;
+mod charts;
+mod codeblock;
 mod content;
 mod diagrams;
 mod favicon;
+mod graphviz;
+mod hexdump;
 mod markdown;
+mod palette;
+mod shortcodes;
 mod templates;
+mod themes;
 mod util;
+mod video;
+mod wavedrom;
 
+use attachments::sha256sums;
+use cast::Cast;
+use charts::{Chart, compile_chart};
 use clap::Parser;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{ContextCompat, Result, WrapErr, bail};
 use content::{ContentReference, SiteConfiguration, Sitemap};
-use diagrams::compile_d2;
+use diagrams::{D2Options, compile_d2, prune_d2_cache};
 use favicon::render_favicon;
-use markdown::Metadata;
+use graphviz::{DotOptions, compile_dot};
+use markdown::{MarkdownDocument, Metadata, is_source_code, load_syntaxes};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use std::{fs::create_dir, path::PathBuf};
+use std::{
+    fs::{create_dir, create_dir_all},
+    path::{Path, PathBuf},
+};
 use templates::{
-    output_archive, output_custom1, output_custom2, output_index, output_rss, output_sitemap,
-    render,
+    output_archive, output_attachment, output_index, output_rss, output_sitemap,
+    output_template_page, render,
 };
+use themes::{html_css, load_themes};
 use tracing::{info, warn};
 use util::FontBook;
 use walkdir::WalkDir;
@@ -34,12 +55,80 @@ fn process_content_first_pass(
             info!("deferring markdown on first pass");
             Ok(())
         }
+        Some("page") => {
+            info!("deferring template page on first pass");
+            Ok(())
+        }
         Some("d2") => {
             info!("rendering d2 diagram");
             content.set_output_extension("svg");
-            content.process(site_config, |source, _, _| {
-                compile_d2(site_config, source.as_bytes())
-            })
+            content
+                .process(site_config, |source, _, content_reference| {
+                    let options = D2Options::from_header(&source)?;
+                    // a standalone diagram becomes one svg, which has no page to hold the stepper's buttons
+                    if options.stepper() {
+                        bail!(
+                            "{content_reference} asks for a stepper, which only d2 fences in markdown can show; \
+                             embed it in a fence or use animate-interval instead"
+                        );
+                    }
+                    compile_d2(site_config, source.as_bytes(), &options)
+                })
+                .wrap_err_with(|| format!("in {content}"))
+        }
+        Some("toml") if content.get_slug().ends_with(".chart") => {
+            info!("rendering chart");
+            content.set_output_extension("svg");
+            content
+                .process(site_config, |source, site_config, content_reference| {
+                    compile_chart(site_config, content_reference, &Chart::parse(&source)?)
+                })
+                .wrap_err_with(|| format!("in {content}"))
+        }
+        Some("cast") => {
+            info!("rendering terminal recording");
+            content.set_output_extension("svg");
+            content
+                .process(site_config, |source, site_config, content_reference| {
+                    let cast = Cast::parse(&source)?;
+                    if let Some(time) = Cast::poster_time(site_config, content_reference)? {
+                        let poster = Cast::poster_path(&content_reference.get_relative_url())
+                            .wrap_err("recording output is not an svg, so it has no poster path")?;
+                        std::fs::write(
+                            site_config.get_output_root().join(poster),
+                            cast.render_poster(site_config, time)?,
+                        )?;
+                    }
+                    cast.render(site_config)
+                })
+                .wrap_err_with(|| format!("in {content}"))
+        }
+        Some("poster")
+            if content
+                .get_source_path(site_config)
+                .with_extension("cast")
+                .is_file() =>
+        {
+            info!("skipping recording poster option");
+            Ok(())
+        }
+        Some("dot" | "gv") => {
+            info!("rendering graphviz diagram");
+            content.set_output_extension("svg");
+            content
+                .process(site_config, |source, _, _| {
+                    compile_dot(site_config, &source, &DotOptions::from_header(&source)?)
+                })
+                .wrap_err_with(|| format!("in {content}"))
+        }
+        Some(_) if content.is_attachment() && is_source_code(Path::new(&content.to_string())) => {
+            info!("copying attachment and rendering viewer page");
+            content.copy(site_config)?;
+            std::fs::write(
+                site_config.get_output_root().join(content.get_viewer_url()),
+                output_attachment(site_config, content)?,
+            )
+            .wrap_err_with(|| format!("in {content}"))
         }
         _ => {
             info!("copying misc file");
@@ -52,15 +141,51 @@ fn process_content_first_pass(
 fn process_content_second_pass(
     site_config: &SiteConfiguration,
     mut content: ContentReference,
-) -> Result<Option<(ContentReference, String, Metadata)>> {
-    if content.get_content_extension() == Some("md") {
-        info!("rendering markdown");
-        content.set_output_extension("html");
-        let document = content.process_with_output(site_config, render)?;
-        Ok(Some((content, document.title, document.metadata)))
-    } else {
-        Ok(None)
+) -> Result<Option<(ContentReference, MarkdownDocument)>> {
+    let mut document = match content.get_content_extension() {
+        Some("md") => {
+            info!("rendering markdown");
+            content.set_output_extension("html");
+            let document = content.process_with_output(site_config, render)?;
+            if document.template.is_some() {
+                bail!("only .page files may name a template");
+            }
+            document
+        }
+        Some("page") => {
+            info!("parsing template page");
+            content.set_output_extension("html");
+            let document =
+                MarkdownDocument::new(content.read(site_config)?, site_config, &content)?;
+            if document.template.is_none() {
+                bail!(".page files must name a template");
+            }
+            document
+        }
+        _ => return Ok(None),
+    };
+    if let Metadata::Page {
+        description: _,
+        modified: modified @ None,
+    } = &mut document.metadata
+    {
+        *modified = Some(content.get_last_modified(site_config)?);
     }
+    Ok(Some((content, document)))
+}
+
+#[tracing::instrument(skip_all, fields(src = content.to_string()))]
+fn process_content_third_pass(
+    site_config: &SiteConfiguration,
+    sitemap: &Sitemap,
+    content: &ContentReference,
+    document: &MarkdownDocument,
+) -> Result<()> {
+    info!("rendering template page");
+    content.write(
+        site_config,
+        output_template_page(site_config, sitemap, content, document)?,
+    )
 }
 
 #[derive(Parser)]
@@ -76,7 +201,19 @@ struct Args {
     url: String,
 
     #[arg(long)]
-    d2: PathBuf,
+    d2: Option<PathBuf>,
+
+    #[arg(long)]
+    d2_cache: Option<PathBuf>,
+
+    #[arg(long)]
+    syntaxes: Option<PathBuf>,
+
+    #[arg(long)]
+    syntaxes_cache: Option<PathBuf>,
+
+    #[arg(long)]
+    themes: Option<PathBuf>,
 
     #[arg(long)]
     font_regular: PathBuf,
@@ -100,11 +237,19 @@ fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     color_eyre::install()?;
 
+    if let Some(syntaxes) = &args.syntaxes {
+        load_syntaxes(syntaxes, args.syntaxes_cache.as_deref())?;
+    }
+    if let Some(themes) = &args.themes {
+        load_themes(themes)?;
+    }
+
     let config = SiteConfiguration::new(
         args.content.canonicalize()?,
         args.dist.canonicalize()?,
         args.url,
         args.d2,
+        args.d2_cache,
         FontBook {
             regular: args.font_regular,
             italic: args.font_italic,
@@ -138,6 +283,21 @@ fn main() -> Result<()> {
         .filter_map(|x| x.transpose())
         .collect::<Result<Vec<_>, _>>()?;
 
+    let assets = config.get_output_root().join("assets");
+    create_dir_all(&assets)?;
+    std::fs::write(
+        assets.join("syntax-highlighting.css"),
+        html_css() + &ansi::html_css(),
+    )?;
+
+    let attachments = config.get_attachments()?;
+    if !attachments.is_empty() {
+        std::fs::write(
+            config.get_output_root().join("attachments/SHA256SUMS"),
+            sha256sums(attachments),
+        )?;
+    }
+
     for content in &mut contents {
         process_content_first_pass(&config, content)?;
     }
@@ -150,6 +310,10 @@ fn main() -> Result<()> {
 
     let sitemap = Sitemap::new(sitemap_entries);
 
+    for (content, document) in sitemap.get_template_pages() {
+        process_content_third_pass(&config, &sitemap, content, document)?;
+    }
+
     std::fs::write(
         config.get_output_root().join("index.html"),
         output_index(&config, &sitemap)?,
@@ -166,20 +330,10 @@ fn main() -> Result<()> {
         config.get_output_root().join("sitemap.xml"),
         output_sitemap(&config, &sitemap)?,
     )?;
-    std::fs::write(
-        config
-            .get_output_root()
-            .join("simple-markov-generator.html"),
-        output_custom1(&config)?,
-    )?;
-    std::fs::write(
-        config
-            .get_output_root()
-            .join("password-strength-checker.html"),
-        output_custom2(&config)?,
-    )?;
 
     render_favicon(&config)?;
 
+    prune_d2_cache(&config)?;
+
     Ok(())
 }
@@ -1,22 +1,52 @@
-use std::{collections::HashMap, sync::LazyLock};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{create_dir_all, read_dir, remove_file},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{LazyLock, OnceLock},
+};
 
 use chrono::{DateTime, NaiveDate};
 use chrono_tz::Tz;
-use color_eyre::eyre::{ContextCompat, Result};
+use color_eyre::eyre::{ContextCompat, Result, WrapErr, bail, eyre};
 use comrak::{
     Arena, ExtensionOptions, Options, ParseOptions, Plugins, RenderOptions, RenderPlugins,
-    adapters::SyntaxHighlighterAdapter, format_html_with_plugins, html, nodes::NodeValue,
+    adapters::SyntaxHighlighterAdapter,
+    format_html_with_plugins, html,
+    nodes::{AstNode, NodeHtmlBlock, NodeLink, NodeValue},
     parse_document,
 };
 use mathemascii::render_mathml;
 use regex::{Captures, Regex, RegexBuilder};
+use sha2::{Digest, Sha256};
 use syntect::{
-    dumps::from_uncompressed_data,
+    dumps::{dump_to_uncompressed_file, from_uncompressed_data, from_uncompressed_dump_file},
     html::{ClassStyle, ClassedHTMLGenerator},
-    parsing::{SyntaxReference, SyntaxSet},
+    parsing::{Scope, SyntaxDefinition, SyntaxReference, SyntaxSet, SyntaxSetBuilder},
     util::LinesWithEndings,
 };
-use tracing::warn;
+use tracing::{info, warn};
+use walkdir::WalkDir;
+
+use crate::{
+    ansi::{self, render_ansi_lines},
+    attachments::{Attachment, render_checksum_table},
+    bytefield::{ByteFieldOptions, compile_bytefield},
+    cast::Cast,
+    charts::{Chart, compile_chart},
+    codeblock::{CodeAttributes, Diff, split_highlighted_lines},
+    content::{ContentReference, SiteConfiguration},
+    diagrams::{Board, D2Options, Diagnostics, compile_d2_boards},
+    graphviz::{DotOptions, compile_dot},
+    hexdump::render_hexdump,
+    shortcodes::{Arguments, expand_shortcodes},
+    templates::check_template_page,
+    themes::svg_css,
+    util::{SourceMap, escape_html},
+    video::{Playback, Video},
+    wavedrom::{WaveOptions, compile_wavedrom},
+};
 
 pub enum Metadata {
     Article {
@@ -26,9 +56,89 @@ pub enum Metadata {
     },
     Page {
         description: String,
+        modified: Option<DateTime<Tz>>,
     },
 }
 
+#[derive(Clone, Copy)]
+pub enum ChangeFrequency {
+    Always,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+    Never,
+}
+
+impl FromStr for ChangeFrequency {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "hourly" => Ok(Self::Hourly),
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            "monthly" => Ok(Self::Monthly),
+            "yearly" => Ok(Self::Yearly),
+            "never" => Ok(Self::Never),
+            _ => Err(eyre!("{s} is not a valid sitemap change frequency")),
+        }
+    }
+}
+
+impl Display for ChangeFrequency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Always => "always",
+            Self::Hourly => "hourly",
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+            Self::Yearly => "yearly",
+            Self::Never => "never",
+        })
+    }
+}
+
+pub struct Indexing {
+    pub noindex: bool,
+    pub sitemap: bool,
+    pub priority: Option<f32>,
+    pub change_frequency: Option<ChangeFrequency>,
+}
+
+impl Indexing {
+    pub fn in_sitemap(&self) -> bool {
+        self.sitemap && !self.noindex
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Layout {
+    Article,
+    Page,
+    Simple,
+    Landing,
+}
+
+impl FromStr for Layout {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "article" => Ok(Self::Article),
+            "page" => Ok(Self::Page),
+            "simple" | "full-bleed" => Ok(Self::Simple),
+            "landing" => Ok(Self::Landing),
+            _ => Err(eyre!(
+                "{s} is not a registered layout; expected article, page, simple or landing"
+            )),
+        }
+    }
+}
+
 pub enum ListingImage {
     ImageUrl(String),
     CodeBlock(String),
@@ -39,43 +149,216 @@ pub struct MarkdownDocument {
     pub title: String,
     pub listing_image: Option<ListingImage>,
     pub metadata: Metadata,
+    pub indexing: Indexing,
+    pub template: Option<String>,
+    pub order: Option<u32>,
+    pub layout: Layout,
+    pub scripts: Vec<String>,
+    pub styles: Vec<String>,
 }
 
-static SS: LazyLock<SyntaxSet> = LazyLock::new(|| {
-    from_uncompressed_data(include_bytes!("../syntaxes/newlines.packdump"))
-        .expect("pre-compiled packdump is invalid")
-});
+const BAKED_SYNTAXES: &[u8] = include_bytes!("../syntaxes/newlines.packdump");
+const SYNTAXES_CACHE_PREFIX: &str = "extra-syntaxes-";
 
-fn syntax_highligh_svg(syntax: &SyntaxReference, input: &str) -> Result<String> {
-    let mut lines = format!(
-        r#"<svg width="640" height="480" viewBox="0 0 640 480" xmlns="http://www.w3.org/2000/svg"><style>{}</style>"#,
-        include_str!("syntax_highlighting.css")
+static SS: OnceLock<SyntaxSet> = OnceLock::new();
+
+fn syntax_set() -> &'static SyntaxSet {
+    SS.get_or_init(|| {
+        from_uncompressed_data(BAKED_SYNTAXES).expect("pre-compiled packdump is invalid")
+    })
+}
+
+// symlinks are followed as syntect would, and every grammar is read once, so the cache key covers
+// exactly the sources that get compiled
+fn read_grammars(folder: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut grammars = Vec::new();
+    for entry in WalkDir::new(folder).follow_links(true).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file()
+            && entry
+                .path()
+                .extension()
+                .is_some_and(|extension| extension == "sublime-syntax")
+        {
+            grammars.push((
+                entry.path().to_path_buf(),
+                std::fs::read_to_string(entry.path())?,
+            ));
+        }
+    }
+    Ok(grammars)
+}
+
+fn syntaxes_cache_key(folder: &Path, grammars: &[(PathBuf, String)]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(BAKED_SYNTAXES);
+    for (path, source) in grammars {
+        hasher.update(path.strip_prefix(folder)?.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(source);
+        hasher.update([0]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn compile_syntaxes(grammars: &[(PathBuf, String)]) -> Result<SyntaxSet> {
+    let mut definitions = Vec::new();
+    for (path, source) in grammars {
+        let name = path.file_stem().and_then(|stem| stem.to_str());
+        definitions.push(
+            SyntaxDefinition::load_from_str(source, true, name)
+                .wrap_err_with(|| format!("in {}", path.to_string_lossy()))?,
+        );
+    }
+    // a grammar from the folder replaces the baked one with its scope instead of shadowing it. baked
+    // grammars are already linked to each other by index, so a replaced one keeps its place for any
+    // that embed it, but loses its scope, name and extensions so no lookup can find it
+    let baked = from_uncompressed_data::<SyntaxSet>(BAKED_SYNTAXES)?.into_builder();
+    let mut builder = SyntaxSetBuilder::new();
+    for syntax in baked.syntaxes() {
+        let mut syntax = syntax.clone();
+        if definitions
+            .iter()
+            .any(|definition| definition.scope == syntax.scope)
+        {
+            syntax.scope = Scope::new(&format!("{}.replaced", syntax.scope))
+                .map_err(|err| eyre!("{err:?}"))?;
+            syntax.name = format!("{} (replaced)", syntax.name);
+            syntax.file_extensions.clear();
+            syntax.first_line_match = None;
+            syntax.hidden = true;
+        }
+        builder.add(syntax);
+    }
+    for definition in definitions {
+        builder.add(definition);
+    }
+    Ok(builder.build())
+}
+
+// dumps for earlier versions of the grammars will never match again; only our own are removed, as
+// the directory may also hold the baked packdump
+fn prune_syntaxes_cache(cache: &Path) -> Result<()> {
+    for entry in read_dir(cache)? {
+        let stale = entry?.path();
+        if stale.file_name().is_some_and(|name| {
+            let name = name.to_string_lossy();
+            name.starts_with(SYNTAXES_CACHE_PREFIX) && name.ends_with(".packdump")
+        }) {
+            remove_file(stale)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn load_syntaxes(folder: &Path, cache: Option<&Path>) -> Result<()> {
+    let grammars = read_grammars(folder)?;
+    let path = cache
+        .map(|cache| -> Result<_> {
+            Ok(cache.join(format!(
+                "{SYNTAXES_CACHE_PREFIX}{}.packdump",
+                syntaxes_cache_key(folder, &grammars)?
+            )))
+        })
+        .transpose()?;
+    let cached = path
+        .as_deref()
+        .filter(|path| path.is_file())
+        .and_then(|path| {
+            from_uncompressed_dump_file(path)
+                .inspect_err(|err| warn!("ignoring unreadable syntax cache: {err}"))
+                .ok()
+        });
+    let syntaxes = match cached {
+        Some(syntaxes) => {
+            info!("using cached syntaxes");
+            syntaxes
+        }
+        None => {
+            info!("compiling syntaxes from {}", folder.to_string_lossy());
+            let syntaxes = compile_syntaxes(&grammars)?;
+            if let Some(path) = &path
+                && let Some(cache) = path.parent()
+            {
+                create_dir_all(cache)?;
+                prune_syntaxes_cache(cache)?;
+                dump_to_uncompressed_file(&syntaxes, path)?;
+            }
+            syntaxes
+        }
+    };
+    SS.set(syntaxes)
+        .map_err(|_| eyre!("syntaxes were loaded after highlighting began"))
+}
+
+fn highlighted_lines_svg<I>(lines: I) -> String
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut svg = format!(
+        r#"<svg width="640" height="480" viewBox="0 0 640 480" xmlns="http://www.w3.org/2000/svg"><style>.source{{font-size:30px;font-family:"Fira Code"}}{}{}</style>"#,
+        svg_css(),
+        ansi::svg_css()
     );
     let padding = 5;
-    for (i, line) in LinesWithEndings::from(input).enumerate() {
-        let mut html_generator =
-            ClassedHTMLGenerator::new_with_class_style(syntax, &SS, ClassStyle::Spaced);
-        html_generator.parse_html_for_line_which_includes_newline(line)?;
-        lines.push_str(&format!(
+    for (i, line) in lines.into_iter().enumerate() {
+        svg.push_str(&format!(
             r#"<text class="source" x="{}" y="{}">"#,
             padding,
             padding + ((i + 1) * 45)
         ));
-        lines.push_str(
-            &html_generator
-                .finalize()
+        svg.push_str(
+            &line
                 .replace("<span", r#"<tspan xml:space="preserve""#)
                 .replace("</span", r#"</tspan"#),
         );
-        lines.push_str("</text>\n");
+        svg.push_str("</text>\n");
     }
-    lines.push_str("</svg>");
-    Ok(lines)
+    svg.push_str("</svg>");
+    svg
+}
+
+fn syntax_highligh_svg(syntax: &SyntaxReference, input: &str) -> Result<String> {
+    let lines = LinesWithEndings::from(input)
+        .map(|line| {
+            let mut html_generator = ClassedHTMLGenerator::new_with_class_style(
+                syntax,
+                syntax_set(),
+                ClassStyle::Spaced,
+            );
+            html_generator.parse_html_for_line_which_includes_newline(line)?;
+            Ok(html_generator.finalize())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(highlighted_lines_svg(lines))
+}
+
+pub fn syntax_token(path: &Path, source: &str) -> String {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| syntax_set().find_syntax_by_extension(extension))
+        .or_else(|| syntax_set().find_syntax_by_first_line(source))
+        .and_then(|syntax| syntax.file_extensions.first())
+        .cloned()
+        .unwrap_or_else(|| String::from("txt"))
+}
+
+pub fn is_source_code(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| syntax_set().find_syntax_by_extension(extension))
+        .is_some_and(|syntax| syntax.name != "Plain Text")
+}
+
+pub fn render_source_listing(path: &Path, source: &str) -> Result<String> {
+    let token = syntax_token(path, source);
+    let html = syntax_highlight_safe(Some(&token), source, OutputFormat::Html);
+    CodeAttributes::anchored().render(&token, &split_highlighted_lines(&html))
 }
 
 fn syntax_highligh_html(syntax: &SyntaxReference, input: &str) -> Result<String> {
     let mut html_generator =
-        ClassedHTMLGenerator::new_with_class_style(syntax, &SS, ClassStyle::Spaced);
+        ClassedHTMLGenerator::new_with_class_style(syntax, syntax_set(), ClassStyle::Spaced);
     for line in LinesWithEndings::from(input) {
         html_generator.parse_html_for_line_which_includes_newline(line)?;
     }
@@ -88,9 +371,26 @@ enum OutputFormat {
 }
 
 fn syntax_highlight_safe(token: Option<&str>, input: &str, format: OutputFormat) -> String {
+    if let Some("ansi" | "console") = token {
+        let lines = render_ansi_lines(input);
+        return match format {
+            OutputFormat::Html => lines.concat(),
+            OutputFormat::Svg => highlighted_lines_svg(lines),
+        };
+    }
+    if let Some(lang) = token.and_then(|token| token.strip_prefix("diff-")) {
+        let diff = Diff::parse(input);
+        let [before, after] = [&diff.before, &diff.after].map(|side| {
+            split_highlighted_lines(&syntax_highlight_safe(Some(lang), side, OutputFormat::Html))
+        });
+        return match format {
+            OutputFormat::Html => diff.render_html(&before, &after),
+            OutputFormat::Svg => highlighted_lines_svg(diff.render_lines(&before, &after)),
+        };
+    }
     let syntax = token
-        .and_then(|token| SS.find_syntax_by_token(token))
-        .or_else(|| SS.find_syntax_by_first_line(input));
+        .and_then(|token| syntax_set().find_syntax_by_token(token))
+        .or_else(|| syntax_set().find_syntax_by_first_line(input));
     if let Some(syntax) = syntax {
         match match format {
             OutputFormat::Html => syntax_highligh_html(syntax, input),
@@ -141,6 +441,306 @@ impl SyntaxHighlighterAdapter for SyntectAdapter {
     }
 }
 
+fn split_info(info: &str) -> (&str, &str) {
+    let info = info.trim();
+    info.split_once(char::is_whitespace).unwrap_or((info, ""))
+}
+
+fn compile_diagram_fence(
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+    lang: &str,
+    args: &str,
+    source: &str,
+) -> Result<(String, String, Vec<Board>)> {
+    let mut args = Arguments::parse(args)?;
+    let alt = args.required("alt")?;
+    let caption = args.optional("caption").unwrap_or_default();
+    let boards = match lang {
+        "d2" => {
+            let mut options = D2Options::from_arguments(&mut args)?;
+            options.default_title(&alt);
+            args.finish()?;
+            compile_d2_boards(config, source.as_bytes(), &options)?
+        }
+        "dot" => {
+            let mut options = DotOptions::from_arguments(&mut args);
+            options.default_title(&alt);
+            args.finish()?;
+            vec![Board {
+                name: String::new(),
+                svg: compile_dot(config, source, &options)?,
+            }]
+        }
+        "chart" => {
+            args.finish()?;
+            let mut chart = Chart::parse(source)?;
+            chart.default_title(&alt);
+            vec![Board {
+                name: String::new(),
+                svg: compile_chart(config, content_reference, &chart)?,
+            }]
+        }
+        "wavedrom" => {
+            let mut options = WaveOptions::from_arguments(&mut args);
+            options.default_title(&alt);
+            args.finish()?;
+            vec![Board {
+                name: String::new(),
+                svg: compile_wavedrom(config, source, &options)?,
+            }]
+        }
+        "bytefield" => {
+            let mut options = ByteFieldOptions::from_arguments(&mut args)?;
+            options.default_title(&alt);
+            args.finish()?;
+            vec![Board {
+                name: String::new(),
+                svg: compile_bytefield(config, source, &options)?,
+            }]
+        }
+        _ => unreachable!("filtered to diagram languages"),
+    };
+    Ok((alt, caption, boards))
+}
+
+fn render_stepper(alt: &str, caption: &str, boards: &[(String, String)]) -> String {
+    let mut html = String::from(r#"<figure class="stepper">"#);
+    for (index, (name, url)) in boards.iter().enumerate() {
+        let label = match name.as_str() {
+            "" => format!("{alt} (step {} of {})", index + 1, boards.len()),
+            name => format!("{alt} (step {} of {}: {name})", index + 1, boards.len()),
+        };
+        html.push_str(&format!(
+            r#"<img src="{}" alt="{}" loading="lazy">"#,
+            escape_html(url),
+            escape_html(&label)
+        ));
+    }
+    html.push_str(concat!(
+        r#"<div class="stepper-controls" hidden>"#,
+        r#"<button type="button" data-step="-1" aria-label="Previous step">&lt;</button>"#,
+        r#"<output aria-live="polite"></output>"#,
+        r#"<button type="button" data-step="1" aria-label="Next step">&gt;</button>"#,
+        "</div>"
+    ));
+    if !caption.is_empty() {
+        html.push_str(&format!(
+            "<figcaption>{}</figcaption>",
+            escape_html(caption)
+        ));
+    }
+    html.push_str("</figure>");
+    html
+}
+
+fn in_fence<T>(result: Result<T>, lang: &str, line: usize, source: &SourceMap) -> Result<T> {
+    let line = source.line(line);
+    result
+        .map_err(|report| match report.downcast::<Diagnostics>() {
+            // the fence body starts on the line after the opening fence
+            Ok(diagnostics) => diagnostics.offset(line).into(),
+            Err(report) => report,
+        })
+        .wrap_err_with(|| {
+            format!(
+                "in {lang} fence on line {line} of {}",
+                source.path().to_string_lossy()
+            )
+        })
+}
+
+fn render_diagram_fences<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    doc: &'a AstNode<'a>,
+    source: &SourceMap,
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+) -> Result<()> {
+    let fences = doc
+        .descendants()
+        .filter_map(|node| match &node.data.borrow().value {
+            NodeValue::CodeBlock(code) if code.fenced => {
+                let (lang, args) = split_info(&code.info);
+                matches!(lang, "d2" | "dot" | "chart" | "wavedrom" | "bytefield").then(|| {
+                    (
+                        node,
+                        lang.to_string(),
+                        args.to_string(),
+                        code.literal.clone(),
+                    )
+                })
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for (index, (node, lang, args, literal)) in fences.into_iter().enumerate() {
+        let line = node.data.borrow().sourcepos.start.line;
+        let (alt, caption, boards) = in_fence(
+            compile_diagram_fence(config, content_reference, &lang, &args, &literal),
+            &lang,
+            line,
+            source,
+        )?;
+        let stem = format!(
+            "images/{}_diagram_{}",
+            content_reference.get_slug(),
+            index + 1
+        );
+        let single = boards.len() == 1;
+        let mut urls = Vec::with_capacity(boards.len());
+        for (board, Board { name, svg }) in boards.into_iter().enumerate() {
+            let url = if single {
+                format!("{stem}.svg")
+            } else {
+                format!("{stem}_{}.svg", board + 1)
+            };
+            let path = content_reference.resolve_relative_path(config, &url);
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            std::fs::write(path, svg)?;
+            urls.push((name, url));
+        }
+        if let [(_, url)] = urls.as_slice() {
+            let image = arena.alloc(
+                NodeValue::Image(NodeLink {
+                    url: url.clone(),
+                    title: caption,
+                })
+                .into(),
+            );
+            image.append(arena.alloc(NodeValue::Text(alt).into()));
+            node.data.borrow_mut().value = NodeValue::Paragraph;
+            node.append(image);
+        } else {
+            node.data.borrow_mut().value = NodeValue::HtmlBlock(NodeHtmlBlock {
+                block_type: 6,
+                literal: render_stepper(&alt, &caption, &urls),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn render_hexdump_fences<'a>(
+    doc: &'a AstNode<'a>,
+    source: &SourceMap,
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+) -> Result<()> {
+    for node in doc.descendants() {
+        let html = match &node.data.borrow().value {
+            NodeValue::CodeBlock(code) if code.fenced => match split_info(&code.info) {
+                ("hexdump", args) => in_fence(
+                    render_hexdump(config, content_reference, args, &code.literal),
+                    "hexdump",
+                    node.data.borrow().sourcepos.start.line,
+                    source,
+                )?,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        node.data.borrow_mut().value = NodeValue::HtmlBlock(NodeHtmlBlock {
+            block_type: 6,
+            literal: html,
+        });
+    }
+    Ok(())
+}
+
+fn render_code_fences<'a>(doc: &'a AstNode<'a>, source: &SourceMap) -> Result<()> {
+    for node in doc.descendants() {
+        let html = match &node.data.borrow().value {
+            NodeValue::CodeBlock(code) if code.fenced => match split_info(&code.info) {
+                (_, "") => continue,
+                (lang, args) => in_fence(
+                    Arguments::parse_info(args).and_then(|mut args| {
+                        let attributes = CodeAttributes::from_arguments(&mut args)?;
+                        args.finish()?;
+                        let highlighted =
+                            syntax_highlight_safe(Some(lang), &code.literal, OutputFormat::Html);
+                        attributes.render(lang, &split_highlighted_lines(&highlighted))
+                    }),
+                    lang,
+                    node.data.borrow().sourcepos.start.line,
+                    source,
+                )?,
+            },
+            _ => continue,
+        };
+        node.data.borrow_mut().value = NodeValue::HtmlBlock(NodeHtmlBlock {
+            block_type: 6,
+            literal: html,
+        });
+    }
+    Ok(())
+}
+
+fn linked_attachment<'c>(
+    config: &'c SiteConfiguration,
+    content_reference: &ContentReference,
+    url: &str,
+) -> Result<Option<&'c Attachment>> {
+    let url = url.split(['#', '?']).next().unwrap_or_default();
+    // viewer pages stand in for the attachment they show
+    let url = url
+        .strip_suffix(".html")
+        .filter(|raw| is_source_code(Path::new(raw)))
+        .unwrap_or(url);
+    if url.is_empty() || url.contains("://") {
+        return Ok(None);
+    }
+    let path = match url.strip_prefix('/') {
+        Some(url) => config.get_content_root().join(url),
+        None => content_reference.resolve_relative_content_path(config, url),
+    };
+    match path.canonicalize().ok().and_then(|path| {
+        Some(
+            path.strip_prefix(config.get_content_root())
+                .ok()?
+                .to_path_buf(),
+        )
+    }) {
+        Some(path) => config.find_attachment(&path),
+        None => Ok(None),
+    }
+}
+
+fn render_checksum_tables<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    doc: &'a AstNode<'a>,
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+) -> Result<()> {
+    let mut listed = Vec::new();
+    for block in doc.children().collect::<Vec<_>>() {
+        let mut linked = Vec::new();
+        for node in block.descendants() {
+            if let NodeValue::Link(link) = &node.data.borrow().value
+                && let Some(attachment) = linked_attachment(config, content_reference, &link.url)?
+                && !listed.contains(&&attachment.path)
+            {
+                listed.push(&attachment.path);
+                linked.push(attachment);
+            }
+        }
+        if !linked.is_empty() {
+            block.insert_after(
+                arena.alloc(
+                    NodeValue::HtmlBlock(NodeHtmlBlock {
+                        block_type: 6,
+                        literal: render_checksum_table(config, &linked),
+                    })
+                    .into(),
+                ),
+            );
+        }
+    }
+    Ok(())
+}
+
 static BLOCK_MATH: LazyLock<Regex> = LazyLock::new(|| {
     RegexBuilder::new(r"\$\$\n(?P<expr>[\w\W]+)\n\$\$")
         .multi_line(true)
@@ -148,16 +748,22 @@ static BLOCK_MATH: LazyLock<Regex> = LazyLock::new(|| {
         .expect("Unreachable - will only panic if the regex is invalid")
 });
 
-fn render_markdown_to_html(md: &str) -> Result<(String, Option<ListingImage>)> {
-    let md = BLOCK_MATH.replace(md, |caps: &Captures| {
-        render_mathml(mathemascii::parse(
+fn render_markdown_to_html(
+    md: &str,
+    source: &SourceMap,
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+) -> Result<(String, Option<ListingImage>)> {
+    let (md, source) = expand_shortcodes(md, source, config, content_reference)?;
+    let (md, source) = source.replace_all(&md, &BLOCK_MATH, |caps: &Captures| {
+        Ok(render_mathml(mathemascii::parse(
             caps.name("expr")
                 .expect(
                     "Unreachable - will only panic if regex doesn't contain a group called 'expr'",
                 )
                 .as_str(),
-        ))
-    });
+        )))
+    })?;
 
     let arena = Arena::new();
 
@@ -206,10 +812,19 @@ fn render_markdown_to_html(md: &str) -> Result<(String, Option<ListingImage>)> {
 
     let doc = parse_document(&arena, &md, &options);
 
+    render_diagram_fences(&arena, doc, &source, config, content_reference)?;
+    render_hexdump_fences(doc, &source, config, content_reference)?;
+
     let first_image_url = doc
         .descendants()
         .filter_map(|node| match &node.data.borrow().value {
-            NodeValue::Image(link) => Some(ListingImage::ImageUrl(link.url.to_string())),
+            NodeValue::Image(link) if Video::is_video(&link.url) => {
+                Video::find_poster(config, content_reference, &link.url).map(ListingImage::ImageUrl)
+            }
+            NodeValue::Image(link) => Some(ListingImage::ImageUrl(
+                Cast::find_poster(config, content_reference, &link.url)
+                    .unwrap_or_else(|| link.url.to_string()),
+            )),
             _ => None,
         })
         .next();
@@ -218,7 +833,7 @@ fn render_markdown_to_html(md: &str) -> Result<(String, Option<ListingImage>)> {
         .descendants()
         .filter_map(|node| match &node.data.borrow().value {
             NodeValue::CodeBlock(code) => Some(ListingImage::CodeBlock(syntax_highlight_safe(
-                Some(&code.info),
+                Some(split_info(&code.info).0),
                 &code.literal,
                 OutputFormat::Svg,
             ))),
@@ -228,6 +843,56 @@ fn render_markdown_to_html(md: &str) -> Result<(String, Option<ListingImage>)> {
 
     let listing_image = first_image_url.or(first_codeblock);
 
+    // after listing selection, which still wants the plain code blocks
+    render_code_fences(doc, &source)?;
+
+    let videos = doc
+        .descendants()
+        .filter(|node| matches!(&node.data.borrow().value, NodeValue::Image(link) if Video::is_video(&link.url)))
+        .collect::<Vec<_>>();
+    for node in videos {
+        let alt = node
+            .descendants()
+            .filter_map(|child| match &child.data.borrow().value {
+                NodeValue::Text(text) => Some(text.to_string()),
+                _ => None,
+            })
+            .collect::<String>();
+        let html =
+            match &node.data.borrow().value {
+                NodeValue::Image(link) => Video::probe(config, content_reference, &link.url)?
+                    .to_html(&Playback::default(), Some(&alt), Some(&link.title)),
+                _ => unreachable!("filtered to images above"),
+            };
+        // a video alone in its paragraph replaces it, as a captioned <figure> cannot sit inside a <p>
+        let paragraph = node.parent().filter(|parent| {
+            matches!(parent.data.borrow().value, NodeValue::Paragraph)
+                && parent
+                    .first_child()
+                    .is_some_and(|child| child.same_node(node))
+                && parent
+                    .last_child()
+                    .is_some_and(|child| child.same_node(node))
+        });
+        match paragraph {
+            Some(paragraph) => {
+                node.detach();
+                paragraph.data.borrow_mut().value = NodeValue::HtmlBlock(NodeHtmlBlock {
+                    block_type: 6,
+                    literal: html,
+                });
+            }
+            None => {
+                for child in node.children().collect::<Vec<_>>() {
+                    child.detach();
+                }
+                node.data.borrow_mut().value = NodeValue::HtmlInline(html);
+            }
+        }
+    }
+
+    render_checksum_tables(&arena, doc, config, content_reference)?;
+
     let mut html = vec![];
     format_html_with_plugins(doc, &options, &mut html, &plugins)?;
 
@@ -241,8 +906,38 @@ fn sloppy_date_parser(s: &str) -> Option<DateTime<Tz>> {
         .and_then(|d| d.and_local_timezone(chrono_tz::Canada::Eastern).earliest())
 }
 
+fn parse_flag(key: &str, s: &str) -> Result<bool> {
+    match s.to_lowercase().as_str() {
+        "true" | "yes" => Ok(true),
+        "false" | "no" => Ok(false),
+        _ => Err(eyre!("{key} must be true or false, not {s}")),
+    }
+}
+
+fn parse_priority(s: &str) -> Result<f32> {
+    let priority = s
+        .parse::<f32>()
+        .wrap_err_with(|| format!("priority {s} is not a number"))?;
+    if !(0.0..=1.0).contains(&priority) {
+        bail!("priority {priority} must be between 0 and 1");
+    }
+    Ok(priority)
+}
+
+fn parse_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(String::from)
+        .collect()
+}
+
 impl MarkdownDocument {
-    pub fn new(content: String) -> Result<Self> {
+    pub fn new(
+        content: String,
+        config: &SiteConfiguration,
+        content_reference: &ContentReference,
+    ) -> Result<Self> {
         let (frontmatter, md) = content
             .split_once("\n\n")
             .wrap_err("Document missing frontmatter")?;
@@ -253,7 +948,14 @@ impl MarkdownDocument {
                     .map(|(key, value)| (key.trim().to_lowercase(), value.trim()))
             })
             .collect();
-        let (content, image_urls) = render_markdown_to_html(md)?;
+        // the body starts after the frontmatter and the blank line that ends it
+        let source = SourceMap::new(
+            content_reference.get_source_path(config),
+            frontmatter.lines().count() + 2,
+            md,
+        );
+        let (content, image_urls) =
+            render_markdown_to_html(md, &source, config, content_reference)?;
         let title = properties
             .get("title")
             .wrap_err("Document missing title")?
@@ -270,15 +972,248 @@ impl MarkdownDocument {
                 category,
             }
         } else if let Some(description) = properties.get("description").map(|x| x.to_string()) {
-            Metadata::Page { description }
+            Metadata::Page {
+                description,
+                modified: properties
+                    .get("modified")
+                    .and_then(|d| sloppy_date_parser(d)),
+            }
         } else {
             panic!("too much missing metadata");
         };
+        let indexing = Indexing {
+            noindex: properties
+                .get("noindex")
+                .map(|x| parse_flag("NoIndex", x))
+                .transpose()?
+                .unwrap_or(false),
+            sitemap: properties
+                .get("sitemap")
+                .map(|x| parse_flag("Sitemap", x))
+                .transpose()?
+                .unwrap_or(true),
+            priority: properties
+                .get("priority")
+                .map(|x| parse_priority(x))
+                .transpose()?,
+            change_frequency: properties
+                .get("changefreq")
+                .map(|x| x.parse())
+                .transpose()?,
+        };
+        let layout = match properties.get("layout") {
+            Some(layout) => layout.parse()?,
+            None => match metadata {
+                Metadata::Article { .. } => Layout::Article,
+                Metadata::Page { .. } => Layout::Page,
+            },
+        };
+        if layout == Layout::Article && !matches!(metadata, Metadata::Article { .. }) {
+            bail!("the article layout requires a date and category");
+        }
+        let template = properties.get("template").map(|x| x.to_string());
+        if let Some(template) = &template {
+            check_template_page(template)?;
+        }
+        let order = properties
+            .get("order")
+            .map(|x| {
+                x.parse::<u32>()
+                    .map_err(|_| eyre!("order {x} must be a whole number"))
+            })
+            .transpose()?;
+        if order.is_some() && template.is_none() {
+            bail!("only template pages are ordered");
+        }
         Ok(Self {
             content,
             title,
             listing_image: image_urls,
             metadata,
+            indexing,
+            template,
+            order,
+            layout,
+            scripts: properties
+                .get("scripts")
+                .map(|x| parse_list(x))
+                .unwrap_or_default(),
+            styles: properties
+                .get("styles")
+                .map(|x| parse_list(x))
+                .unwrap_or_default(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::OptionExt;
+
+    use super::*;
+
+    const OVERRIDE: &str = "%YAML 1.2
+---
+name: Settings
+scope: source.ini
+file_extensions: [ini]
+contexts:
+  main:
+    - match: '\\w+'
+      scope: keyword.other.ini
+";
+
+    const EXTRA: &str = "%YAML 1.2
+---
+name: Extra
+scope: source.extra
+file_extensions: [extra]
+contexts:
+  main: []
+";
+
+    #[test]
+    fn syntaxes_cache_key_follows_grammars() -> Result<()> {
+        let folder = Path::new("grammars");
+        let grammars = vec![(folder.join("a.sublime-syntax"), EXTRA.to_string())];
+        let key = syntaxes_cache_key(folder, &grammars)?;
+        assert_eq!(key, syntaxes_cache_key(folder, &grammars.clone())?);
+        assert_ne!(key, syntaxes_cache_key(folder, &[])?);
+        let edited = vec![(folder.join("a.sublime-syntax"), format!("{EXTRA}\n"))];
+        assert_ne!(key, syntaxes_cache_key(folder, &edited)?);
+        let renamed = vec![(folder.join("b.sublime-syntax"), EXTRA.to_string())];
+        assert_ne!(key, syntaxes_cache_key(folder, &renamed)?);
+        // the folder itself is not part of the key, so moving the checkout keeps the cache
+        let moved = vec![(
+            Path::new("elsewhere/a.sublime-syntax").to_path_buf(),
+            EXTRA.to_string(),
+        )];
+        assert_eq!(key, syntaxes_cache_key(Path::new("elsewhere"), &moved)?);
+        Ok(())
+    }
+
+    #[test]
+    fn compiled_grammars_replace_baked_ones() -> Result<()> {
+        let baked = syntax_set();
+        let syntaxes = compile_syntaxes(&[
+            (PathBuf::from("INI.sublime-syntax"), OVERRIDE.to_string()),
+            (PathBuf::from("Extra.sublime-syntax"), EXTRA.to_string()),
+        ])?;
+        let ini = syntaxes
+            .syntaxes()
+            .iter()
+            .filter(|syntax| syntax.scope.to_string() == "source.ini")
+            .collect::<Vec<_>>();
+        assert_eq!(ini.len(), 1);
+        assert_eq!(ini[0].name, "Settings");
+        assert!(syntaxes.find_syntax_by_name("INI").is_none());
+        assert_eq!(
+            syntaxes
+                .find_syntax_by_extension("ini")
+                .map(|syntax| syntax.name.as_str()),
+            Some("Settings")
+        );
+        assert!(syntaxes.find_syntax_by_extension("extra").is_some());
+        assert_eq!(syntaxes.syntaxes().len(), baked.syntaxes().len() + 2);
+        // grammars that were linked against the baked set still highlight
+        let rust = syntaxes
+            .find_syntax_by_token("rust")
+            .ok_or_eyre("rust is baked")?;
+        let mut generator =
+            ClassedHTMLGenerator::new_with_class_style(rust, &syntaxes, ClassStyle::Spaced);
+        generator.parse_html_for_line_which_includes_newline("fn main() {}\n")?;
+        assert!(generator.finalize().contains("entity name function"));
+        Ok(())
+    }
+
+    #[test]
+    fn pruning_keeps_other_packdumps() -> Result<()> {
+        let cache = std::env::temp_dir().join(format!("syntaxes-cache-{}", std::process::id()));
+        create_dir_all(&cache)?;
+        let stale = cache.join(format!("{SYNTAXES_CACHE_PREFIX}0123.packdump"));
+        let baked = cache.join("newlines.packdump");
+        std::fs::write(&stale, b"")?;
+        std::fs::write(&baked, b"")?;
+        prune_syntaxes_cache(&cache)?;
+        let (stale_exists, baked_exists) = (stale.exists(), baked.exists());
+        std::fs::remove_dir_all(&cache)?;
+        assert!(!stale_exists);
+        assert!(baked_exists);
+        Ok(())
+    }
+
+    #[test]
+    fn fence_diagnostics_point_into_the_file() -> Result<()> {
+        // the body starts on line 4 of the file, after the front matter
+        let body = "text\n\n```d2\na -> b\nb ->\n```";
+        let source = SourceMap::new(PathBuf::from("post.md"), 4, body);
+        let diagnostics = Diagnostics::single(2, 5, String::from("missing target"));
+        let Err(report) = in_fence::<()>(Err(diagnostics.into()), "d2", 3, &source) else {
+            bail!("a diagnostic is still an error");
+        };
+        assert_eq!(report.to_string(), "in d2 fence on line 6 of post.md");
+        assert_eq!(
+            report.root_cause().to_string(),
+            "line 8, column 5: missing target"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stepper_figure() {
+        let html = render_stepper(
+            "Login flow",
+            "Steps <1>",
+            &[
+                (String::new(), String::from("images/flow_1.svg")),
+                (String::from("2"), String::from("images/flow_2.svg")),
+            ],
+        );
+        assert!(html.starts_with(r#"<figure class="stepper"><img src="images/flow_1.svg" alt="Login flow (step 1 of 2)" loading="lazy"><img src="images/flow_2.svg" alt="Login flow (step 2 of 2: 2)" loading="lazy">"#));
+        // the controls only appear once the script has wired them up
+        assert!(html.contains(r#"<div class="stepper-controls" hidden>"#));
+        assert!(html.ends_with("<figcaption>Steps &lt;1&gt;</figcaption></figure>"));
+        assert!(!render_stepper("", "", &[]).contains("figcaption"));
+    }
+
+    #[test]
+    fn flags() -> Result<()> {
+        for (value, expected) in [
+            ("true", true),
+            ("Yes", true),
+            ("FALSE", false),
+            ("no", false),
+        ] {
+            assert_eq!(parse_flag("NoIndex", value)?, expected);
+        }
+        for value in ["", "1", "on", "maybe"] {
+            assert!(parse_flag("NoIndex", value).is_err(), "{value}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn priorities() -> Result<()> {
+        assert_eq!(parse_priority("0")?, 0.0);
+        assert_eq!(parse_priority("0.8")?, 0.8);
+        assert_eq!(parse_priority("1.0")?, 1.0);
+        for value in ["1.5", "-0.1", "NaN", "inf", "high", ""] {
+            assert!(parse_priority(value).is_err(), "{value}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn change_frequencies() -> Result<()> {
+        for name in [
+            "always", "hourly", "daily", "weekly", "monthly", "yearly", "never",
+        ] {
+            assert_eq!(name.parse::<ChangeFrequency>()?.to_string(), name);
+        }
+        assert_eq!("Weekly".parse::<ChangeFrequency>()?.to_string(), "weekly");
+        for value in ["", "fortnightly", "week", "sometimes"] {
+            assert!(value.parse::<ChangeFrequency>().is_err(), "{value}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::LazyLock;
+
+use color_eyre::eyre::{Result, bail};
+use regex::{Captures, Regex};
+
+const BASE_CSS: &str = include_str!("../templates/base.css");
+
+static VARIABLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"var\((?P<name>--[\w-]+)\)").expect("compile time regex invalid"));
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scheme {
+    Light,
+    Dark,
+}
+
+struct Property {
+    scheme: Option<Scheme>,
+    name: String,
+    value: String,
+}
+
+// the custom properties declared in base.css, tagged with the colour scheme block they sit in
+static PROPERTIES: LazyLock<Vec<Property>> = LazyLock::new(|| {
+    let mut properties = Vec::new();
+    let mut scheme = None;
+    let mut depth = 0;
+    for line in BASE_CSS.lines().map(str::trim) {
+        if depth == 0 {
+            scheme = match line {
+                "@media (prefers-color-scheme: dark) {" => Some(Scheme::Dark),
+                "@media (prefers-color-scheme: light) {" => Some(Scheme::Light),
+                _ => None,
+            };
+        }
+        depth += line.matches('{').count();
+        depth -= line.matches('}').count();
+        if let Some((name, value)) = line
+            .strip_suffix(';')
+            .and_then(|line| line.split_once(':'))
+            .filter(|(name, _)| name.starts_with("--"))
+        {
+            properties.push(Property {
+                scheme,
+                name: name.to_string(),
+                value: value.trim().to_string(),
+            });
+        }
+    }
+    properties
+});
+
+pub fn property(name: &str, scheme: Scheme) -> Option<&'static str> {
+    let find = |scheme| {
+        PROPERTIES
+            .iter()
+            .find(|property| property.scheme == scheme && property.name == name)
+    };
+    find(Some(scheme))
+        .or_else(|| find(None))
+        .map(|property| property.value.as_str())
+}
+
+// svgs are standalone files, so var() references are swapped for the values base.css gives them
+pub fn resolve(value: &str, scheme: Scheme) -> Result<String> {
+    if let Some(name) = VARIABLE
+        .captures_iter(value)
+        .map(|captures| captures["name"].to_string())
+        .find(|name| property(name, scheme).is_none())
+    {
+        bail!("{name} is not declared in base.css");
+    }
+    Ok(VARIABLE
+        .replace_all(value, |captures: &Captures| {
+            property(&captures["name"], scheme).unwrap_or_default()
+        })
+        .to_string())
+}
@@ -0,0 +1,496 @@
+use std::{collections::HashMap, ops::Range, path::Path, str::FromStr, sync::LazyLock};
+
+use color_eyre::eyre::{Result, WrapErr, bail, eyre};
+use regex::Regex;
+
+use crate::{
+    content::{ContentReference, SiteConfiguration},
+    markdown::syntax_token,
+    util::{SourceMap, escape_html},
+    video::{Playback, Video},
+};
+
+static SHORTCODE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{\{<\s*(?P<name>[\w-]+)(?P<args>.*?)>\}\}").expect("compile time regex invalid")
+});
+
+static ARGUMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"\s*(?P<key>[\w-]+)="(?P<value>[^"]*)""#).expect("compile time regex invalid")
+});
+
+static INFO_ARGUMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"\s*(?P<key>[\w-]+)(?:="(?P<value>[^"]*)"|=(?P<bare>[^\s"]+))?"#)
+        .expect("compile time regex invalid")
+});
+
+pub struct Arguments {
+    values: HashMap<String, String>,
+}
+
+impl Arguments {
+    pub fn parse(source: &str) -> Result<Self> {
+        Self::parse_with(&ARGUMENT, source)
+    }
+
+    // fence info strings also allow bare flags and unquoted values, as in linenos start=120
+    pub fn parse_info(source: &str) -> Result<Self> {
+        Self::parse_with(&INFO_ARGUMENT, source)
+    }
+
+    fn parse_with(pattern: &Regex, source: &str) -> Result<Self> {
+        let mut values = HashMap::new();
+        let mut remainder = source.trim();
+        while !remainder.is_empty() {
+            let captures = pattern
+                .captures(remainder)
+                .filter(|captures| captures.get(0).is_some_and(|m| m.start() == 0))
+                .ok_or_else(|| eyre!("malformed arguments near {remainder}"))?;
+            let key = captures["key"].to_string();
+            let value = captures
+                .name("value")
+                .or_else(|| captures.name("bare"))
+                .map_or("true", |value| value.as_str());
+            if values.insert(key.clone(), value.to_string()).is_some() {
+                bail!("argument {key} given more than once");
+            }
+            remainder = remainder[captures[0].len()..].trim_start();
+        }
+        Ok(Self { values })
+    }
+
+    pub fn required(&mut self, key: &str) -> Result<String> {
+        self.values
+            .remove(key)
+            .ok_or_else(|| eyre!("missing required argument {key}"))
+    }
+
+    pub fn optional(&mut self, key: &str) -> Option<String> {
+        self.values.remove(key)
+    }
+
+    pub fn parsed<T>(&mut self, key: &str, default: T) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.values
+            .remove(key)
+            .map(|value| {
+                value
+                    .parse()
+                    .wrap_err_with(|| format!("argument {key} has invalid value {value}"))
+            })
+            .transpose()
+            .map(|value| value.unwrap_or(default))
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self.values.into_keys().next() {
+            Some(key) => Err(eyre!("unknown argument {key}")),
+            None => Ok(()),
+        }
+    }
+}
+
+struct ShortcodeContext<'a> {
+    config: &'a SiteConfiguration,
+    content_reference: &'a ContentReference,
+}
+
+impl ShortcodeContext<'_> {
+    fn require_file(&self, path: &str) -> Result<()> {
+        let resolved = self
+            .content_reference
+            .resolve_relative_content_path(self.config, path);
+        if resolved.is_file() {
+            Ok(())
+        } else {
+            Err(eyre!("{} does not exist", resolved.to_string_lossy()))
+        }
+    }
+}
+
+fn video(context: &ShortcodeContext, mut args: Arguments) -> Result<String> {
+    let src = args.required("src")?;
+    let autoplay = args.parsed("autoplay", false)?;
+    let playback = Playback {
+        autoplay,
+        looping: args.parsed("loop", false)?,
+        muted: args.parsed("muted", autoplay)?,
+        controls: args.parsed("controls", !autoplay)?,
+    };
+    let label = args.optional("label");
+    let caption = args.optional("caption");
+    args.finish()?;
+    Ok(
+        Video::probe(context.config, context.content_reference, &src)?.to_html(
+            &playback,
+            label.as_deref(),
+            caption.as_deref(),
+        ),
+    )
+}
+
+fn figure(context: &ShortcodeContext, mut args: Arguments) -> Result<String> {
+    let src = args.required("src")?;
+    let alt = args.required("alt")?;
+    let caption = args.optional("caption");
+    args.finish()?;
+    context.require_file(&src)?;
+    if src.contains(['<', '>']) {
+        bail!("{src} is not a valid image path");
+    }
+    // emitted as a markdown image so that it remains a listing image candidate
+    let alt = alt
+        .replace('\\', "\\\\")
+        .replace('[', "\\[")
+        .replace(']', "\\]");
+    Ok(match caption {
+        Some(caption) => format!(
+            r#"![{alt}](<{src}> "{}")"#,
+            caption.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        None => format!("![{alt}](<{src}>)"),
+    })
+}
+
+fn callout(_context: &ShortcodeContext, mut args: Arguments) -> Result<String> {
+    let kind = args
+        .optional("kind")
+        .unwrap_or_else(|| String::from("note"));
+    let text = args.required("text")?;
+    let title = args.optional("title");
+    args.finish()?;
+    let default_title = match kind.as_str() {
+        "note" => "Note",
+        "tip" => "Tip",
+        "important" => "Important",
+        "warning" => "Warning",
+        "caution" => "Caution",
+        _ => {
+            bail!("{kind} is not a callout kind; expected note, tip, important, warning or caution")
+        }
+    };
+    Ok(format!(
+        r#"<div class="markdown-alert markdown-alert-{kind}"><p class="markdown-alert-title">{}</p><p>{}</p></div>"#,
+        escape_html(title.as_deref().unwrap_or(default_title)),
+        escape_html(&text)
+    ))
+}
+
+fn toy(context: &ShortcodeContext, mut args: Arguments) -> Result<String> {
+    let src = args.required("src")?;
+    let title = args.required("title")?;
+    let height = args.parsed("height", 400u32)?;
+    args.finish()?;
+    let page = src
+        .strip_suffix(".html")
+        .ok_or_else(|| eyre!("{src} is not a web toy page"))?;
+    context.require_file(&format!("{page}.page"))?;
+    Ok(format!(
+        r#"<iframe class="toy" src="{}" title="{}" height="{height}" loading="lazy"></iframe>"#,
+        escape_html(&src),
+        escape_html(&title)
+    ))
+}
+
+fn select_lines<'a>(
+    lines: &[&'a str],
+    range: Option<&str>,
+    region: Option<&str>,
+) -> Result<(usize, Vec<&'a str>)> {
+    match (range, region) {
+        (Some(_), Some(_)) => bail!("give either lines or region, not both"),
+        (Some(range), None) => {
+            let parse = |number: &str| {
+                number
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| eyre!("{range} is not a line range such as 10-40"))
+            };
+            let (first, last) = match range.split_once('-') {
+                Some((first, "")) => (parse(first)?, lines.len()),
+                Some((first, last)) => (parse(first)?, parse(last)?),
+                None => (parse(range)?, parse(range)?),
+            };
+            if first == 0 || last < first || last > lines.len() {
+                bail!("lines {range} are outside the file's {} lines", lines.len());
+            }
+            Ok((first, lines[first - 1..last].to_vec()))
+        }
+        (None, Some(region)) => {
+            let (start, end) = (format!("[start:{region}]"), format!("[end:{region}]"));
+            let first = lines
+                .iter()
+                .position(|line| line.contains(&start))
+                .ok_or_else(|| eyre!("no {start} marker"))?
+                + 1;
+            let length = lines[first..]
+                .iter()
+                .position(|line| line.contains(&end))
+                .ok_or_else(|| eyre!("no {end} marker after {start}"))?;
+            Ok((first + 1, lines[first..first + length].to_vec()))
+        }
+        (None, None) => Ok((1, lines.to_vec())),
+    }
+}
+
+fn include(context: &ShortcodeContext, mut args: Arguments) -> Result<String> {
+    let src = args.required("src")?;
+    let range = args.optional("lines");
+    let region = args.optional("region");
+    let linenos = args.parsed("linenos", range.is_some() || region.is_some())?;
+    let title = args.optional("title");
+    let hl_lines = args.optional("hl_lines");
+    args.finish()?;
+    let path = context
+        .content_reference
+        .resolve_relative_content_path(context.config, &src);
+    let source = std::fs::read_to_string(&path)
+        .map_err(|err| eyre!("error opening {}: {err}", path.to_string_lossy()))?;
+    let lines = source.lines().collect::<Vec<_>>();
+    let (start, mut excerpt) = select_lines(&lines, range.as_deref(), region.as_deref())?;
+    // regions usually sit inside a block, so drop the indentation they share
+    let indent = excerpt
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    for line in &mut excerpt {
+        *line = line.get(indent..).unwrap_or_default();
+    }
+    let code = excerpt.join("\n");
+
+    let title = title.unwrap_or_else(|| {
+        Path::new(&src)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| src.clone())
+    });
+    // info string values are quoted without escapes, so a quote would end them early
+    for (key, value) in [("src", &src), ("title", &title)] {
+        if value.contains('"') {
+            bail!("{key} {value} cannot contain a double quote");
+        }
+    }
+    let mut info = format!(
+        r#"{} title="{}" download="{src}" start={start} linenos={linenos}"#,
+        syntax_token(&path, &source),
+        title
+    );
+    if let Some(hl_lines) = hl_lines {
+        info.push_str(&format!(r#" hl_lines="{hl_lines}""#));
+    }
+    // a tilde fence longer than any run in the code cannot be closed early by it
+    let fence = "~".repeat(
+        code.split(|c| c != '~')
+            .map(str::len)
+            .max()
+            .unwrap_or(0)
+            .max(2)
+            + 1,
+    );
+    Ok(format!("{fence}{info}\n{code}\n{fence}"))
+}
+
+fn expand_shortcode(context: &ShortcodeContext, name: &str, args: &str) -> Result<String> {
+    let args = Arguments::parse(args)?;
+    match name {
+        "video" => video(context, args),
+        "figure" => figure(context, args),
+        "callout" => callout(context, args),
+        "toy" => toy(context, args),
+        "include" => include(context, args),
+        _ => Err(eyre!("no shortcode named {name}")),
+    }
+}
+
+// byte ranges of fenced code blocks, whose contents are shown as written
+fn fenced_regions(md: &str) -> Vec<Range<usize>> {
+    let mut regions = Vec::new();
+    let mut open: Option<(usize, char, usize)> = None;
+    let mut position = 0;
+    for line in md.split_inclusive('\n') {
+        let start = position;
+        position += line.len();
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let line = line.trim();
+        let Some(marker) = line.chars().next().filter(|c| matches!(c, '`' | '~')) else {
+            continue;
+        };
+        let length = line.len() - line.trim_start_matches(marker).len();
+        match open {
+            Some((begin, fence, fence_length))
+                if marker == fence && length >= fence_length && length == line.len() =>
+            {
+                regions.push(begin..position);
+                open = None;
+            }
+            None if indent < 4 && length >= 3 => open = Some((start, marker, length)),
+            _ => {}
+        }
+    }
+    if let Some((begin, _, _)) = open {
+        regions.push(begin..md.len());
+    }
+    regions
+}
+
+pub fn expand_shortcodes(
+    md: &str,
+    source: &SourceMap,
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+) -> Result<(String, SourceMap)> {
+    let context = ShortcodeContext {
+        config,
+        content_reference,
+    };
+    let fenced = fenced_regions(md);
+    source.replace_all(md, &SHORTCODE, |captures| {
+        let whole = captures.get(0).expect("group 0 always matches");
+        if fenced.iter().any(|region| region.contains(&whole.start())) {
+            return Ok(whole.as_str().to_string());
+        }
+        expand_shortcode(&context, &captures["name"], &captures["args"])
+            .wrap_err_with(|| format!("in shortcode {}", whole.as_str()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shortcodes_outside_fences(md: &str) -> Vec<&str> {
+        let fenced = fenced_regions(md);
+        SHORTCODE
+            .find_iter(md)
+            .filter(|found| !fenced.iter().any(|region| region.contains(&found.start())))
+            .map(|found| found.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn quoted_arguments() -> Result<()> {
+        let mut args = Arguments::parse(r#" src="a b.mp4" caption="" "#)?;
+        assert_eq!(args.required("src")?, "a b.mp4");
+        assert_eq!(args.optional("caption").as_deref(), Some(""));
+        args.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn unquoted_arguments_only_in_info_strings() -> Result<()> {
+        assert!(Arguments::parse("start=120").is_err());
+        let mut args = Arguments::parse_info(r#"linenos start=120 title="a b""#)?;
+        assert!(args.parsed("linenos", false)?);
+        assert_eq!(args.parsed("start", 1usize)?, 120);
+        assert_eq!(args.optional("title").as_deref(), Some("a b"));
+        args.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_argument() {
+        assert!(Arguments::parse(r#"src="a" src="b""#).is_err());
+    }
+
+    #[test]
+    fn unknown_argument() -> Result<()> {
+        let mut args = Arguments::parse(r#"src="a" colour="red""#)?;
+        args.required("src")?;
+        assert!(args.finish().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_required_argument() -> Result<()> {
+        let mut args = Arguments::parse(r#"alt="a""#)?;
+        assert!(args.required("src").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_parsed_argument() -> Result<()> {
+        let mut args = Arguments::parse(r#"height="tall""#)?;
+        assert!(args.parsed("height", 400u32).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn shortcodes_in_fences_are_left_alone() {
+        let md = r#"{{< toy src="a.html" >}}
+
+```markdown
+{{< toy src="b.html" >}}
+```
+
+~~~~
+```
+{{< toy src="c.html" >}}
+~~~
+~~~~
+
+    ```
+{{< toy src="d.html" >}}
+
+~~~
+{{< toy src="e.html" >}}
+"#;
+        assert_eq!(
+            shortcodes_outside_fences(md),
+            [r#"{{< toy src="a.html" >}}"#, r#"{{< toy src="d.html" >}}"#]
+        );
+    }
+
+    #[test]
+    fn line_ranges() -> Result<()> {
+        let lines = ["one", "two", "three", "four"];
+        assert_eq!(
+            select_lines(&lines, Some("2-3"), None)?,
+            (2, vec!["two", "three"])
+        );
+        assert_eq!(
+            select_lines(&lines, Some("3-"), None)?,
+            (3, vec!["three", "four"])
+        );
+        assert_eq!(select_lines(&lines, Some("4"), None)?, (4, vec!["four"]));
+        assert_eq!(select_lines(&lines, None, None)?.1.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn line_ranges_out_of_bounds() {
+        let lines = ["one", "two", "three", "four"];
+        for range in ["3-2", "0-2", "4-5", "5", "5-", "a-b", ""] {
+            assert!(
+                select_lines(&lines, Some(range), None).is_err(),
+                "{range} was accepted"
+            );
+        }
+        assert!(select_lines(&lines, Some("1-2"), Some("main")).is_err());
+    }
+
+    #[test]
+    fn regions() -> Result<()> {
+        let lines = [
+            "fn main() {",
+            "    // [start:greeting]",
+            "    println!(\"hello\");",
+            "    println!(\"world\");",
+            "    // [end:greeting]",
+            "}",
+        ];
+        assert_eq!(
+            select_lines(&lines, None, Some("greeting"))?,
+            (
+                3,
+                vec!["    println!(\"hello\");", "    println!(\"world\");"]
+            )
+        );
+        assert!(select_lines(&lines, None, Some("farewell")).is_err());
+        assert!(select_lines(&lines[..4], None, Some("greeting")).is_err());
+        Ok(())
+    }
+}
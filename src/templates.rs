@@ -1,10 +1,11 @@
-use std::io::Cursor;
+use std::{io::Cursor, path::Path};
 
 use askama::Template;
 use chrono::{DateTime, Datelike};
 use chrono_tz::Tz;
-use color_eyre::eyre::{Context, Result};
+use color_eyre::eyre::{Context, ContextCompat, OptionExt, Result, eyre};
 use image::{DynamicImage, ImageReader, Rgb, RgbImage, buffer::ConvertBuffer, imageops};
+use itertools::Itertools;
 use ntscrs::ntsc::{
     FbmNoiseSettings, NtscEffect, TrackingNoiseSettings, VHSEdgeWaveSettings, VHSSettings,
     VHSTapeSpeed,
@@ -15,9 +16,10 @@ use tracing::info;
 
 use crate::{
     ContentReference,
-    content::ArticleEntry,
-    markdown::{ListingImage, MarkdownDocument, Metadata},
-    util::render_svg,
+    attachments::Attachment,
+    content::{ArticleEntry, TemplateEntry},
+    markdown::{Layout, ListingImage, MarkdownDocument, Metadata, render_source_listing},
+    util::{human_size, render_svg},
 };
 use crate::{SiteConfiguration, content::Sitemap};
 
@@ -35,9 +37,36 @@ fn iso_date(date: &DateTime<Tz>) -> String {
     format!("{}", date.format("%+"))
 }
 
+#[derive(Template)]
+#[template(path = "article.html")]
+struct ArticleLayout<'a> {
+    config: &'a SiteConfiguration,
+    content_reference: &'a ContentReference,
+    document: &'a MarkdownDocument,
+    opengraph_image_url: String,
+}
+
 #[derive(Template)]
 #[template(path = "page.html")]
-struct PageTemplate<'a> {
+struct PageLayout<'a> {
+    config: &'a SiteConfiguration,
+    content_reference: &'a ContentReference,
+    document: &'a MarkdownDocument,
+    opengraph_image_url: String,
+}
+
+#[derive(Template)]
+#[template(path = "simple-page.html")]
+struct SimpleLayout<'a> {
+    config: &'a SiteConfiguration,
+    content_reference: &'a ContentReference,
+    document: &'a MarkdownDocument,
+    opengraph_image_url: String,
+}
+
+#[derive(Template)]
+#[template(path = "landing.html")]
+struct LandingLayout<'a> {
     config: &'a SiteConfiguration,
     content_reference: &'a ContentReference,
     document: &'a MarkdownDocument,
@@ -159,7 +188,7 @@ pub fn render(
     config: &SiteConfiguration,
     content_reference: &ContentReference,
 ) -> Result<(String, MarkdownDocument)> {
-    let document = MarkdownDocument::new(source)?;
+    let document = MarkdownDocument::new(source, config, content_reference)?;
     let opengraph_image_url = format!("images/opengraph_{}.jpeg", content_reference.get_slug());
     let (listing_image, opengraph_image) = render_og(config, content_reference, &document)?;
     opengraph_image.save(content_reference.resolve_relative_path(config, &opengraph_image_url))?;
@@ -167,16 +196,77 @@ pub fn render(
         config,
         format!("images/listing_{}.jpeg", content_reference.get_slug()),
     ))?;
-    Ok((
-        PageTemplate {
+    let opengraph_image_url = config.resolve_relative_url(&opengraph_image_url);
+    let output = match document.layout {
+        Layout::Article => ArticleLayout {
             config,
             content_reference,
             document: &document,
-            opengraph_image_url: config.resolve_relative_url(&opengraph_image_url),
+            opengraph_image_url,
         }
         .render()?,
-        document,
-    ))
+        Layout::Page => PageLayout {
+            config,
+            content_reference,
+            document: &document,
+            opengraph_image_url,
+        }
+        .render()?,
+        Layout::Simple => SimpleLayout {
+            config,
+            content_reference,
+            document: &document,
+            opengraph_image_url,
+        }
+        .render()?,
+        Layout::Landing => LandingLayout {
+            config,
+            content_reference,
+            document: &document,
+            opengraph_image_url,
+        }
+        .render()?,
+    };
+    Ok((output, document))
+}
+
+#[derive(Template)]
+#[template(path = "attachment.html")]
+struct AttachmentPage<'a> {
+    config: &'a SiteConfiguration,
+    canonical_url: String,
+    name: String,
+    size: String,
+    attachment: &'a Attachment,
+    signature: Option<String>,
+    listing: String,
+}
+
+pub fn output_attachment(
+    config: &SiteConfiguration,
+    content_reference: &ContentReference,
+) -> Result<String> {
+    let source = content_reference.read(config)?;
+    let url = content_reference.get_relative_url();
+    let path = Path::new(&url);
+    let attachment = config
+        .find_attachment(path)?
+        .wrap_err("attachment missing from the checksum manifest")?;
+    let file_name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+    Ok(AttachmentPage {
+        config,
+        canonical_url: config.resolve_relative_url(&content_reference.get_viewer_url()),
+        name: file_name(path),
+        size: human_size(attachment.size),
+        attachment,
+        signature: attachment.signature.as_deref().map(file_name),
+        listing: render_source_listing(path, &source)?,
+    }
+    .render()?)
 }
 
 #[derive(Template)]
@@ -227,22 +317,79 @@ pub fn output_sitemap(config: &SiteConfiguration, sitemap: &Sitemap) -> Result<S
     Ok(SitemapPage { config, sitemap }.render()?)
 }
 
-#[derive(Template)]
-#[template(path = "simple-markov-generator.html")]
-struct Custom1<'a> {
+pub struct TemplatePage<'a> {
     config: &'a SiteConfiguration,
+    content_reference: &'a ContentReference,
+    document: &'a MarkdownDocument,
+    things: Vec<TemplateEntry<'a>>,
 }
 
-pub fn output_custom1(config: &SiteConfiguration) -> Result<String> {
-    Ok(Custom1 { config }.render()?)
+type TemplatePageRenderer = fn(&TemplatePage) -> askama::Result<String>;
+
+// every template page shares one context; each entry only binds a name to its template file
+macro_rules! template_pages {
+    ($($name:literal => $path:tt),* $(,)?) => {
+        const TEMPLATE_PAGES: &[(&str, TemplatePageRenderer)] = &[$(
+            ($name, |page| {
+                #[derive(Template)]
+                #[template(path = $path)]
+                struct TemplateLayout<'a, 'b>(&'a TemplatePage<'b>);
+
+                impl<'b> std::ops::Deref for TemplateLayout<'_, 'b> {
+                    type Target = TemplatePage<'b>;
+
+                    fn deref(&self) -> &Self::Target {
+                        self.0
+                    }
+                }
+
+                TemplateLayout(page).render()
+            }),
+        )*];
+    };
 }
 
-#[derive(Template)]
-#[template(path = "password-strength-checker.html")]
-struct Custom2<'a> {
-    config: &'a SiteConfiguration,
+template_pages! {
+    "simple-markov-generator" => "simple-markov-generator.html",
+    "password-strength-checker" => "password-strength-checker.html",
+    "web-things" => "web-things.html",
 }
 
-pub fn output_custom2(config: &SiteConfiguration) -> Result<String> {
-    Ok(Custom2 { config }.render()?)
+pub fn check_template_page(name: &str) -> Result<()> {
+    if TEMPLATE_PAGES.iter().any(|(template, _)| *template == name) {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "no template page named {name}; expected one of {}",
+            TEMPLATE_PAGES
+                .iter()
+                .map(|(template, _)| *template)
+                .join(", ")
+        ))
+    }
+}
+
+pub fn output_template_page(
+    config: &SiteConfiguration,
+    sitemap: &Sitemap,
+    content_reference: &ContentReference,
+    document: &MarkdownDocument,
+) -> Result<String> {
+    let name = document
+        .template
+        .as_deref()
+        .ok_or_eyre("document does not name a template")?;
+    let (_, render) = TEMPLATE_PAGES
+        .iter()
+        .find(|(template, _)| *template == name)
+        .ok_or_else(|| eyre!("no template page named {name}"))?;
+    Ok(render(&TemplatePage {
+        config,
+        content_reference,
+        document,
+        things: sitemap
+            .get_template_entries()
+            .filter(|entry| entry.relative_url != content_reference.get_relative_url())
+            .collect(),
+    })?)
 }
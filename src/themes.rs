@@ -0,0 +1,288 @@
+use std::{io::Cursor, path::Path, sync::OnceLock};
+
+use color_eyre::eyre::{OptionExt, Result, bail, eyre};
+use tracing::info;
+use walkdir::WalkDir;
+use xmltree::{Element, XMLNode};
+
+use crate::palette::Scheme;
+
+const BAKED_THEMES: [(&str, &str); 2] = [
+    (
+        "outurnate.dark.tmTheme",
+        include_str!("../syntaxes/outurnate.dark.tmTheme"),
+    ),
+    (
+        "outurnate.light.tmTheme",
+        include_str!("../syntaxes/outurnate.light.tmTheme"),
+    ),
+];
+
+static THEMES: OnceLock<Vec<Theme>> = OnceLock::new();
+
+struct Rule {
+    selectors: Vec<String>,
+    foreground: Option<String>,
+    background: Option<String>,
+    font_style: Vec<String>,
+}
+
+struct Theme {
+    scheme: Option<Scheme>,
+    rules: Vec<Rule>,
+}
+
+enum Plist {
+    String(String),
+    Array(Vec<Plist>),
+    Dict(Vec<(String, Plist)>),
+    Other,
+}
+
+impl Plist {
+    fn parse(element: &Element) -> Result<Self> {
+        let children = element.children.iter().filter_map(XMLNode::as_element);
+        Ok(match element.name.as_str() {
+            "string" => Self::String(element.get_text().unwrap_or_default().to_string()),
+            "array" => Self::Array(children.map(Self::parse).collect::<Result<_>>()?),
+            "dict" => {
+                let mut entries = Vec::new();
+                let mut children = children;
+                while let Some(key) = children.next() {
+                    if key.name != "key" {
+                        bail!("expected a key in plist dict, found {}", key.name);
+                    }
+                    let value = children
+                        .next()
+                        .ok_or_eyre("plist dict key is missing its value")?;
+                    entries.push((
+                        key.get_text().unwrap_or_default().to_string(),
+                        Self::parse(value)?,
+                    ));
+                }
+                Self::Dict(entries)
+            }
+            _ => Self::Other,
+        })
+    }
+
+    fn get(&self, key: &str) -> Option<&Self> {
+        match self {
+            Self::Dict(entries) => entries
+                .iter()
+                .find_map(|(name, value)| (name == key).then_some(value)),
+            _ => None,
+        }
+    }
+
+    fn string(&self, key: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(Self::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn colour(value: Option<&str>) -> Result<Option<String>> {
+    let Some(colour) = value else {
+        return Ok(None);
+    };
+    match colour.strip_prefix('#') {
+        Some(hex) if matches!(hex.len(), 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(Some(colour.to_lowercase()))
+        }
+        _ => Err(eyre!("{colour} is not a #rrggbb or #rrggbbaa colour")),
+    }
+}
+
+// ClassStyle::Spaced gives each atom of a scope its own class, so scopes map onto class chains
+fn selector(scopes: &str) -> String {
+    scopes
+        .split_whitespace()
+        .map(|scope| {
+            scope
+                .split('.')
+                .map(|atom| {
+                    let mut class = String::from(".");
+                    for c in atom.chars() {
+                        if !c.is_ascii_alphanumeric() && c != '-' && c != '_' {
+                            class.push('\\');
+                        }
+                        class.push(c);
+                    }
+                    class
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Theme {
+    // the scheme comes from the file name, as in name.dark.tmTheme; themes without one apply to both
+    fn parse(file_name: &str, source: &str) -> Result<Self> {
+        let scheme = match file_name.strip_suffix(".tmTheme") {
+            Some(stem) if stem.ends_with(".dark") => Some(Scheme::Dark),
+            Some(stem) if stem.ends_with(".light") => Some(Scheme::Light),
+            _ => None,
+        };
+        let root = Element::parse(Cursor::new(source))?;
+        let plist = Plist::parse(
+            root.children
+                .iter()
+                .find_map(XMLNode::as_element)
+                .ok_or_eyre("tmTheme plist is empty")?,
+        )?;
+        let Some(Plist::Array(items)) = plist.get("settings") else {
+            bail!("tmTheme has no settings array");
+        };
+        let mut rules = Vec::new();
+        for item in items {
+            let Some(settings) = item.get("settings") else {
+                continue;
+            };
+            // the unscoped entry holds the defaults for all source text
+            let selectors = match item.string("scope") {
+                Some(scope) => scope
+                    .split(',')
+                    // exclusions have no class equivalent, so they are dropped
+                    .filter_map(|scopes| scopes.split(" - ").next())
+                    .map(selector)
+                    .filter(|selector| !selector.is_empty())
+                    // scoped to .source, which both highlighted code and hexdumps carry
+                    .map(|selector| format!(".source {selector}"))
+                    .collect(),
+                None => vec![String::from(".source")],
+            };
+            rules.push(Rule {
+                selectors,
+                foreground: colour(settings.string("foreground"))?,
+                background: colour(settings.string("background"))?,
+                font_style: settings
+                    .string("fontStyle")
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect(),
+            });
+        }
+        Ok(Self { scheme, rules })
+    }
+
+    fn css(&self, svg: bool) -> String {
+        let mut css = String::new();
+        for rule in &self.rules {
+            let mut declarations = Vec::new();
+            // tspans cannot have a background, so the svg draws highlighted text in that colour
+            match svg {
+                true => {
+                    if let Some(fill) = rule.background.as_ref().or(rule.foreground.as_ref()) {
+                        declarations.push(format!("fill: {};", fill));
+                    }
+                }
+                false => {
+                    if let Some(foreground) = &rule.foreground {
+                        declarations.push(format!("color: {};", foreground));
+                    }
+                    if let Some(background) = &rule.background {
+                        declarations.push(format!("background-color: {};", background));
+                    }
+                }
+            }
+            for style in &rule.font_style {
+                declarations.push(match style.as_str() {
+                    "bold" => String::from("font-weight: bold;"),
+                    "italic" => String::from("font-style: italic;"),
+                    "underline" => String::from("text-decoration: underline;"),
+                    _ => continue,
+                });
+            }
+            if declarations.is_empty() || rule.selectors.is_empty() {
+                continue;
+            }
+            css.push_str(&format!(
+                "{} {{\n{}\n}}\n\n",
+                rule.selectors.join(",\n"),
+                declarations
+                    .iter()
+                    .map(|declaration| format!("    {declaration}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
+        css
+    }
+}
+
+fn themes() -> &'static [Theme] {
+    THEMES.get_or_init(|| {
+        BAKED_THEMES
+            .iter()
+            .map(|(file_name, source)| {
+                Theme::parse(file_name, source).expect("baked theme is invalid")
+            })
+            .collect()
+    })
+}
+
+// themes in the folder replace the baked ones entirely, so a lone dark theme does not mix with it
+pub fn load_themes(folder: &Path) -> Result<()> {
+    let mut themes = Vec::new();
+    for entry in WalkDir::new(folder).sort_by_file_name() {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy();
+        if entry.file_type().is_file() && file_name.ends_with(".tmTheme") {
+            info!("loading theme {file_name}");
+            themes.push(
+                Theme::parse(&file_name, &std::fs::read_to_string(entry.path())?)
+                    .map_err(|err| eyre!("in {}: {err}", entry.path().to_string_lossy()))?,
+            );
+        }
+    }
+    if themes.is_empty() {
+        return Ok(());
+    }
+    THEMES
+        .set(themes)
+        .map_err(|_| eyre!("themes were loaded after highlighting began"))
+}
+
+pub fn html_css() -> String {
+    let mut css = String::new();
+    for (scheme, media) in [
+        (None, None),
+        (Some(Scheme::Dark), Some("dark")),
+        (Some(Scheme::Light), Some("light")),
+    ] {
+        let rules = themes()
+            .iter()
+            .filter(|theme| theme.scheme == scheme)
+            .map(|theme| theme.css(false))
+            .collect::<String>();
+        match media {
+            _ if rules.is_empty() => {}
+            Some(media) => {
+                css.push_str(&format!("@media (prefers-color-scheme: {media}) {{\n"));
+                for line in rules.trim_end().lines() {
+                    if line.is_empty() {
+                        css.push('\n');
+                    } else {
+                        css.push_str(&format!("    {line}\n"));
+                    }
+                }
+                css.push_str("}\n\n");
+            }
+            None => css.push_str(&rules),
+        }
+    }
+    css
+}
+
+// listing images are drawn on black, so they take the dark scheme
+pub fn svg_css() -> String {
+    themes()
+        .iter()
+        .filter(|theme| theme.scheme != Some(Scheme::Light))
+        .map(|theme| theme.css(true))
+        .collect()
+}
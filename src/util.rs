@@ -1,7 +1,12 @@
-use std::{fs::File, io::Read, path::PathBuf};
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use color_eyre::eyre::{ContextCompat, Result};
 use image::RgbaImage;
+use regex::{Captures, Regex};
 use resvg::{
     tiny_skia::Pixmap,
     usvg::{self, Transform, fontdb},
@@ -34,6 +39,26 @@ impl FontBook {
         fontdb.load_font_data(monospace);
         Ok(())
     }
+
+    fn family(path: &PathBuf) -> Result<String> {
+        let mut fontdb = fontdb::Database::new();
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        fontdb.load_font_data(data);
+        fontdb
+            .faces()
+            .find_map(|face| face.families.first())
+            .map(|(family, _)| family.clone())
+            .wrap_err_with(|| format!("{} has no family name", path.to_string_lossy()))
+    }
+
+    pub fn regular_family(&self) -> Result<String> {
+        Self::family(&self.regular)
+    }
+
+    pub fn monospace_family(&self) -> Result<String> {
+        Self::family(&self.monospace)
+    }
 }
 
 pub fn render_svg(
@@ -64,3 +89,155 @@ pub fn render_svg(
     );
     RgbaImage::from_raw(width, height, pixmap.take()).wrap_err("pixmap geometry unexpected")
 }
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn human_size(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} bytes"),
+        1024..1048576 => format!("{:.1} KiB ({bytes} bytes)", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB ({bytes} bytes)", bytes as f64 / 1048576.0),
+    }
+}
+
+// maps each line of rewritten text back to the line of the source file it came from
+pub struct SourceMap {
+    path: PathBuf,
+    lines: Vec<usize>,
+}
+
+impl SourceMap {
+    pub fn new(path: PathBuf, first_line: usize, text: &str) -> Self {
+        let count = text.split('\n').count();
+        Self {
+            path,
+            lines: (first_line..first_line + count).collect(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn line(&self, line: usize) -> usize {
+        self.lines
+            .get(line.saturating_sub(1))
+            .or(self.lines.last())
+            .copied()
+            .unwrap_or(line)
+    }
+
+    // every line of a replacement maps to the line its match started on
+    pub fn replace_all(
+        &self,
+        text: &str,
+        regex: &Regex,
+        mut replacer: impl FnMut(&Captures) -> Result<String>,
+    ) -> Result<(String, Self)> {
+        let mut output = String::with_capacity(text.len());
+        let mut lines = vec![self.line(1)];
+        let mut line = 1;
+        let mut last = 0;
+        for captures in regex.captures_iter(text) {
+            let whole = captures.get(0).expect("group 0 always matches");
+            for _ in text[last..whole.start()].matches('\n') {
+                line += 1;
+                lines.push(self.line(line));
+            }
+            output.push_str(&text[last..whole.start()]);
+            let replacement = replacer(&captures)?;
+            lines.extend(replacement.matches('\n').map(|_| self.line(line)));
+            output.push_str(&replacement);
+            line += whole.as_str().matches('\n').count();
+            last = whole.end();
+        }
+        for _ in text[last..].matches('\n') {
+            line += 1;
+            lines.push(self.line(line));
+        }
+        output.push_str(&text[last..]);
+        Ok((
+            output,
+            Self {
+                path: self.path.clone(),
+                lines,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::bail;
+
+    use super::*;
+
+    fn lines(map: &SourceMap, count: usize) -> Vec<usize> {
+        (1..=count).map(|line| map.line(line)).collect()
+    }
+
+    #[test]
+    fn replacements_map_to_their_match() -> Result<()> {
+        let regex = Regex::new(r"\{\{(\w+)\}\}")?;
+        let source = SourceMap::new(PathBuf::from("post.md"), 10, "a\n{{x}}\nb\n{{y}}c\nd");
+        let (text, map) = source.replace_all("a\n{{x}}\nb\n{{y}}c\nd", &regex, |captures| {
+            Ok(match &captures[1] {
+                "x" => String::from("1\n2\n3"),
+                _ => String::new(),
+            })
+        })?;
+        assert_eq!(text, "a\n1\n2\n3\nb\nc\nd");
+        assert_eq!(lines(&map, 7), [10, 11, 11, 11, 12, 13, 14]);
+        assert_eq!(map.path(), Path::new("post.md"));
+        // lines past the end stay on the last one
+        assert_eq!(map.line(20), 14);
+        Ok(())
+    }
+
+    #[test]
+    fn multiline_matches_skip_their_lines() -> Result<()> {
+        let regex = Regex::new(r"<<\n>>")?;
+        let source = SourceMap::new(PathBuf::from("post.md"), 1, "a\n<<\n>>\nb");
+        let (text, map) = source.replace_all("a\n<<\n>>\nb", &regex, |_| Ok(String::from("z")))?;
+        assert_eq!(text, "a\nz\nb");
+        assert_eq!(lines(&map, 3), [1, 2, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn maps_compose() -> Result<()> {
+        let regex = Regex::new(r"@")?;
+        let source = SourceMap::new(PathBuf::from("post.md"), 5, "@\nx\n@");
+        let (text, first) = source.replace_all("@\nx\n@", &regex, |_| Ok(String::from("a\nb")))?;
+        let (text, second) =
+            first.replace_all(&text, &Regex::new(r"x")?, |_| Ok(String::from("x\nx\nx")))?;
+        assert_eq!(text, "a\nb\nx\nx\nx\na\nb");
+        assert_eq!(lines(&second, 7), [5, 5, 6, 6, 6, 7, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn replacer_errors_propagate() -> Result<()> {
+        let regex = Regex::new(r"x")?;
+        let source = SourceMap::new(PathBuf::from("post.md"), 1, "x");
+        assert!(
+            source
+                .replace_all("x", &regex, |_| bail!("no expansion"))
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,492 @@
+use std::path::Path;
+
+use color_eyre::eyre::{ContextCompat, Result, bail, eyre};
+
+use crate::{
+    content::{ContentReference, SiteConfiguration},
+    util::escape_html,
+};
+
+const EBML_SEGMENT: u64 = 0x18538067;
+const EBML_TRACKS: u64 = 0x1654AE6B;
+const EBML_TRACK_ENTRY: u64 = 0xAE;
+const EBML_VIDEO: u64 = 0xE0;
+const EBML_PIXEL_WIDTH: u64 = 0xB0;
+const EBML_PIXEL_HEIGHT: u64 = 0xBA;
+const EBML_DISPLAY_WIDTH: u64 = 0x54B0;
+const EBML_DISPLAY_HEIGHT: u64 = 0x54BA;
+
+const POSTER_EXTENSIONS: [&str; 4] = ["webp", "jpeg", "jpg", "png"];
+
+// the dimensions sit four levels down in either container, so anything deeper is not a real file
+const MAX_DEPTH: usize = 8;
+
+fn read_vint(data: &[u8], keep_marker: bool) -> Option<(u64, usize, bool)> {
+    let first = *data.first()?;
+    let length = first.leading_zeros() as usize + 1;
+    if length > 8 || data.len() < length {
+        return None;
+    }
+    let marker = if keep_marker {
+        0
+    } else {
+        0x80u8 >> (length - 1)
+    };
+    let mut value = u64::from(first & !marker);
+    for byte in &data[1..length] {
+        value = (value << 8) | u64::from(*byte);
+    }
+    let all_ones = !keep_marker && value == (1u64 << (7 * length)) - 1;
+    Some((value, length, all_ones))
+}
+
+fn read_uint(data: &[u8]) -> u64 {
+    data.iter()
+        .fold(0, |value, byte| (value << 8) | u64::from(*byte))
+}
+
+fn walk_ebml(data: &[u8], dimensions: &mut [Option<u64>; 4], depth: usize) -> Result<()> {
+    if depth > MAX_DEPTH {
+        bail!("EBML elements nested too deeply");
+    }
+    let mut position = 0;
+    while position < data.len() {
+        let (id, id_length, _) =
+            read_vint(&data[position..], true).wrap_err("truncated EBML element id")?;
+        let (size, size_length, unknown_size) = read_vint(&data[position + id_length..], false)
+            .wrap_err("truncated EBML element size")?;
+        let start = position + id_length + size_length;
+        let end = if unknown_size {
+            data.len()
+        } else {
+            usize::try_from(size)?
+                .checked_add(start)
+                .filter(|end| *end <= data.len())
+                .wrap_err("truncated EBML element")?
+        };
+        let body = &data[start..end];
+        match id {
+            EBML_SEGMENT | EBML_TRACKS | EBML_TRACK_ENTRY | EBML_VIDEO => {
+                walk_ebml(body, dimensions, depth + 1)?
+            }
+            EBML_PIXEL_WIDTH => dimensions[0] = Some(read_uint(body)),
+            EBML_PIXEL_HEIGHT => dimensions[1] = Some(read_uint(body)),
+            EBML_DISPLAY_WIDTH => dimensions[2] = Some(read_uint(body)),
+            EBML_DISPLAY_HEIGHT => dimensions[3] = Some(read_uint(body)),
+            _ => {}
+        }
+        if dimensions[0].is_some() && dimensions[1].is_some() {
+            return Ok(());
+        }
+        position = end;
+    }
+    Ok(())
+}
+
+fn probe_webm(data: &[u8]) -> Result<(u32, u32)> {
+    let mut dimensions = [None; 4];
+    walk_ebml(data, &mut dimensions, 0)?;
+    let [pixel_width, pixel_height, display_width, display_height] = dimensions;
+    let width = display_width
+        .or(pixel_width)
+        .wrap_err("webm has no video track")?;
+    let height = display_height
+        .or(pixel_height)
+        .wrap_err("webm has no video track")?;
+    Ok((u32::try_from(width)?, u32::try_from(height)?))
+}
+
+fn walk_mp4(data: &[u8], depth: usize) -> Result<Option<(u32, u32)>> {
+    if depth > MAX_DEPTH {
+        bail!("mp4 boxes nested too deeply");
+    }
+    let mut position = 0;
+    while position + 8 <= data.len() {
+        let size = read_uint(&data[position..position + 4]);
+        let kind = &data[position + 4..position + 8];
+        let (start, size) = match size {
+            0 => (position + 8, (data.len() - position) as u64),
+            1 => (
+                position + 16,
+                read_uint(
+                    data.get(position + 8..position + 16)
+                        .wrap_err("truncated mp4 box")?,
+                ),
+            ),
+            size => (position + 8, size),
+        };
+        let end = usize::try_from(size)?
+            .checked_add(position)
+            .filter(|end| *end <= data.len())
+            .wrap_err("truncated mp4 box")?;
+        if end < start {
+            bail!("mp4 box smaller than its header");
+        }
+        let body = &data[start..end];
+        match kind {
+            b"moov" | b"trak" => {
+                if let Some(dimensions) = walk_mp4(body, depth + 1)? {
+                    return Ok(Some(dimensions));
+                }
+            }
+            b"tkhd" => {
+                let offset = match body.first() {
+                    Some(1) => 88,
+                    Some(0) => 76,
+                    _ => bail!("unsupported mp4 track header version"),
+                };
+                let fixed = body
+                    .get(offset..offset + 8)
+                    .wrap_err("truncated mp4 track header")?;
+                let width = read_uint(&fixed[..4]) >> 16;
+                let height = read_uint(&fixed[4..]) >> 16;
+                if width != 0 && height != 0 {
+                    return Ok(Some((u32::try_from(width)?, u32::try_from(height)?)));
+                }
+            }
+            _ => {}
+        }
+        position = end;
+    }
+    Ok(None)
+}
+
+fn probe_mp4(data: &[u8]) -> Result<(u32, u32)> {
+    walk_mp4(data, 0)?.wrap_err("mp4 has no video track")
+}
+
+pub struct Playback {
+    pub autoplay: bool,
+    pub looping: bool,
+    pub muted: bool,
+    pub controls: bool,
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self {
+            autoplay: true,
+            looping: true,
+            muted: true,
+            controls: false,
+        }
+    }
+}
+
+struct Captions {
+    src: String,
+    language: Option<String>,
+}
+
+pub struct Video {
+    src: String,
+    mime: &'static str,
+    width: u32,
+    height: u32,
+    poster: Option<String>,
+    captions: Vec<Captions>,
+}
+
+impl Video {
+    pub fn is_video(src: &str) -> bool {
+        Self::mime(src).is_some()
+    }
+
+    fn mime(src: &str) -> Option<&'static str> {
+        match Path::new(src).extension().and_then(|ext| ext.to_str()) {
+            Some("webm") => Some("video/webm"),
+            Some("mp4") => Some("video/mp4"),
+            _ => None,
+        }
+    }
+
+    fn sidecar(
+        config: &SiteConfiguration,
+        content_reference: &ContentReference,
+        src: &str,
+        extensions: &[&str],
+    ) -> Option<String> {
+        extensions.iter().find_map(|ext| {
+            let sidecar = Path::new(src).with_extension(ext);
+            content_reference
+                .resolve_relative_content_path(config, &sidecar)
+                .is_file()
+                .then(|| sidecar.to_string_lossy().to_string())
+        })
+    }
+
+    pub fn find_poster(
+        config: &SiteConfiguration,
+        content_reference: &ContentReference,
+        src: &str,
+    ) -> Option<String> {
+        Self::sidecar(config, content_reference, src, &POSTER_EXTENSIONS)
+    }
+
+    // clip.vtt has no stated language; clip.fr.vtt and the like are tracks in that language
+    fn find_captions(
+        config: &SiteConfiguration,
+        content_reference: &ContentReference,
+        src: &str,
+    ) -> Result<Vec<Captions>> {
+        let mut captions = Vec::new();
+        if let Some(src) = Self::sidecar(config, content_reference, src, &["vtt"]) {
+            captions.push(Captions {
+                src,
+                language: None,
+            });
+        }
+        let src = Path::new(src);
+        let Some(stem) = src.file_stem().map(|stem| stem.to_string_lossy()) else {
+            return Ok(captions);
+        };
+        let folder = content_reference
+            .resolve_relative_content_path(config, src)
+            .with_file_name("");
+        let mut languages = Vec::new();
+        for entry in std::fs::read_dir(&folder)
+            .map_err(|err| eyre!("error listing {}: {err}", folder.to_string_lossy()))?
+        {
+            let file_name = entry?.file_name();
+            if let Some(language) = file_name
+                .to_string_lossy()
+                .strip_prefix(&format!("{stem}."))
+                .and_then(|name| name.strip_suffix(".vtt"))
+                .filter(|language| {
+                    !language.is_empty()
+                        && language
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                })
+            {
+                languages.push(language.to_string());
+            }
+        }
+        languages.sort();
+        captions.extend(languages.into_iter().map(|language| {
+            Captions {
+                src: src
+                    .with_file_name(format!("{stem}.{language}.vtt"))
+                    .to_string_lossy()
+                    .to_string(),
+                language: Some(language),
+            }
+        }));
+        Ok(captions)
+    }
+
+    pub fn probe(
+        config: &SiteConfiguration,
+        content_reference: &ContentReference,
+        src: &str,
+    ) -> Result<Self> {
+        let mime = Self::mime(src).ok_or_else(|| eyre!("{src} is not a webm or mp4 video"))?;
+        let path = content_reference.resolve_relative_content_path(config, src);
+        let data = std::fs::read(&path)
+            .map_err(|err| eyre!("error opening {}: {err}", path.to_string_lossy()))?;
+        let (width, height) = match mime {
+            "video/webm" => probe_webm(&data)?,
+            _ => probe_mp4(&data)?,
+        };
+        Ok(Self {
+            src: src.to_string(),
+            mime,
+            width,
+            height,
+            poster: Self::find_poster(config, content_reference, src),
+            captions: Self::find_captions(config, content_reference, src)?,
+        })
+    }
+
+    pub fn to_html(
+        &self,
+        playback: &Playback,
+        label: Option<&str>,
+        caption: Option<&str>,
+    ) -> String {
+        let mut html = format!(
+            r#"<video width="{}" height="{}" preload="metadata""#,
+            self.width, self.height
+        );
+        // autoplay is started by script so that prefers-reduced-motion can veto it
+        for (attribute, enabled) in [
+            ("muted playsinline", playback.muted),
+            ("data-autoplay", playback.autoplay),
+            ("loop", playback.looping),
+            ("controls", playback.controls),
+        ] {
+            if enabled {
+                html.push(' ');
+                html.push_str(attribute);
+            }
+        }
+        if let Some(poster) = &self.poster {
+            html.push_str(&format!(r#" poster="{}""#, escape_html(poster)));
+        }
+        if let Some(label) = label.filter(|label| !label.is_empty()) {
+            html.push_str(&format!(r#" aria-label="{}""#, escape_html(label)));
+        }
+        html.push_str(&format!(
+            r#"><source src="{}" type="{}">"#,
+            escape_html(&self.src),
+            self.mime
+        ));
+        for (index, captions) in self.captions.iter().enumerate() {
+            html.push_str(&format!(
+                r#"<track kind="captions" src="{}""#,
+                escape_html(&captions.src)
+            ));
+            if let Some(language) = &captions.language {
+                html.push_str(&format!(r#" srclang="{language}" label="{language}""#));
+            }
+            if index == 0 {
+                html.push_str(" default");
+            }
+            html.push('>');
+        }
+        html.push_str("</video>");
+        match caption.filter(|caption| !caption.is_empty()) {
+            Some(caption) => format!(
+                "<figure>{html}<figcaption>{}</figcaption></figure>",
+                escape_html(caption)
+            ),
+            None => html,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut element = id.to_vec();
+        element.push(0x80 | u8::try_from(body.len()).expect("test element fits a one byte size"));
+        element.extend_from_slice(body);
+        element
+    }
+
+    fn webm() -> Vec<u8> {
+        let video = [
+            element(&[0xB0], &[0x02, 0x80]),
+            element(&[0xBA], &[0x01, 0xE0]),
+        ]
+        .concat();
+        let tracks = element(
+            &[0x16, 0x54, 0xAE, 0x6B],
+            &element(&[0xAE], &element(&[0xE0], &video)),
+        );
+        // the segment has an unknown size, as a live recording would
+        let mut data = element(&[0x1A, 0x45, 0xDF, 0xA3], &element(&[0x42, 0x82], b"webm"));
+        data.extend_from_slice(&[
+            0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ]);
+        data.extend(tracks);
+        data
+    }
+
+    fn mp4_box(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = u32::try_from(body.len() + 8)
+            .expect("test box fits a u32")
+            .to_be_bytes()
+            .to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn mp4() -> Vec<u8> {
+        let mut tkhd = vec![0; 84];
+        tkhd[76..80].copy_from_slice(&(1280u32 << 16).to_be_bytes());
+        tkhd[80..84].copy_from_slice(&(720u32 << 16).to_be_bytes());
+        [
+            mp4_box(b"ftyp", b"isom\0\0\0\0"),
+            mp4_box(b"moov", &mp4_box(b"trak", &mp4_box(b"tkhd", &tkhd))),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn webm_dimensions() -> Result<()> {
+        assert_eq!(probe_webm(&webm())?, (640, 480));
+        Ok(())
+    }
+
+    #[test]
+    fn mp4_dimensions() -> Result<()> {
+        assert_eq!(probe_mp4(&mp4())?, (1280, 720));
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let (webm, mp4) = (webm(), mp4());
+        for length in 0..webm.len() {
+            assert!(probe_webm(&webm[..length]).is_err(), "webm cut at {length}");
+        }
+        for length in 0..mp4.len() {
+            assert!(probe_mp4(&mp4[..length]).is_err(), "mp4 cut at {length}");
+        }
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        for data in [
+            &[][..],
+            &[0x00; 16],
+            &[0xFF; 16],
+            b"not a video file at all",
+        ] {
+            assert!(probe_webm(data).is_err());
+            assert!(probe_mp4(data).is_err());
+        }
+        // a box claiming to be smaller than its own header
+        assert!(probe_mp4(&[0, 0, 0, 4, b'm', b'o', b'o', b'v']).is_err());
+        // a 64 bit size that overflows
+        let mut huge = vec![0, 0, 0, 1];
+        huge.extend_from_slice(b"moov");
+        huge.extend_from_slice(&[0xFF; 8]);
+        assert!(probe_mp4(&huge).is_err());
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let segments = [
+            0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ]
+        .repeat(100_000);
+        assert!(probe_webm(&segments).is_err());
+        let mut boxes = Vec::new();
+        for _ in 0..100_000 {
+            boxes.extend_from_slice(&[0, 0, 0, 0]);
+            boxes.extend_from_slice(b"moov");
+        }
+        assert!(probe_mp4(&boxes).is_err());
+    }
+
+    #[test]
+    fn caption_languages() {
+        let video = Video {
+            src: String::from("clip.webm"),
+            mime: "video/webm",
+            width: 640,
+            height: 480,
+            poster: None,
+            captions: vec![
+                Captions {
+                    src: String::from("clip.vtt"),
+                    language: None,
+                },
+                Captions {
+                    src: String::from("clip.fr.vtt"),
+                    language: Some(String::from("fr")),
+                },
+            ],
+        };
+        let html = video.to_html(&Playback::default(), None, None);
+        assert!(html.contains(r#"<track kind="captions" src="clip.vtt" default>"#));
+        assert!(
+            html.contains(r#"<track kind="captions" src="clip.fr.vtt" srclang="fr" label="fr">"#)
+        );
+    }
+}
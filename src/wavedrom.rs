@@ -0,0 +1,602 @@
+use color_eyre::eyre::{Result, bail, eyre};
+use serde_json::{Map, Value};
+use xmltree::{Element, XMLNode};
+
+use crate::{
+    content::SiteConfiguration,
+    diagrams::{Diagnostics, DiagramLabels, element, path, text, text_width, themed_style},
+    shortcodes::Arguments,
+};
+
+const TICK: f64 = 20.0;
+const ROW: f64 = 30.0;
+const HIGH: f64 = 5.0;
+const MID: f64 = 15.0;
+const LOW: f64 = 25.0;
+const SLANT: f64 = 3.0;
+const MARGIN: f64 = 10.0;
+const INDENT: f64 = 12.0;
+const FONT_SIZE: f64 = 13.0;
+const BUS_COLOURS: usize = 3;
+
+pub struct WaveOptions {
+    labels: DiagramLabels,
+}
+
+impl WaveOptions {
+    pub fn from_arguments(args: &mut Arguments) -> Self {
+        Self {
+            labels: DiagramLabels::from_arguments(args),
+        }
+    }
+
+    pub fn default_title(&mut self, title: &str) {
+        self.labels.default_title(title);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Low { sharp: bool, arrow: bool },
+    High { sharp: bool, arrow: bool },
+    Clock { positive: bool, arrow: bool },
+    HighZ,
+    Undefined,
+    Data(usize),
+}
+
+impl State {
+    fn parse(c: char) -> Option<Self> {
+        Some(match c {
+            '0' | 'd' => Self::Low {
+                sharp: false,
+                arrow: false,
+            },
+            '1' | 'u' => Self::High {
+                sharp: false,
+                arrow: false,
+            },
+            'l' => Self::Low {
+                sharp: true,
+                arrow: false,
+            },
+            'h' => Self::High {
+                sharp: true,
+                arrow: false,
+            },
+            'L' => Self::Low {
+                sharp: true,
+                arrow: true,
+            },
+            'H' => Self::High {
+                sharp: true,
+                arrow: true,
+            },
+            'p' | 'P' => Self::Clock {
+                positive: true,
+                arrow: c == 'P',
+            },
+            'n' | 'N' => Self::Clock {
+                positive: false,
+                arrow: c == 'N',
+            },
+            'z' => Self::HighZ,
+            'x' => Self::Undefined,
+            '=' | '2' => Self::Data(0),
+            '3'..='9' => Self::Data((c as usize - '2' as usize) % BUS_COLOURS),
+            _ => return None,
+        })
+    }
+}
+
+struct Run {
+    state: State,
+    start: f64,
+    length: f64,
+    period: f64,
+    label: Option<String>,
+}
+
+struct Signal {
+    name: String,
+    depth: usize,
+    runs: Vec<Run>,
+    gaps: Vec<f64>,
+}
+
+enum Lane {
+    Signal(Signal),
+    Group(String, usize),
+    Spacer,
+}
+
+fn text_field(object: &Map<String, Value>, key: &str) -> Result<Option<String>> {
+    match object.get(key) {
+        None => Ok(None),
+        Some(Value::String(text)) => Ok(Some(text.clone())),
+        Some(Value::Number(number)) => Ok(Some(number.to_string())),
+        Some(_) => bail!("{key} must be plain text"),
+    }
+}
+
+fn number_field(object: &Map<String, Value>, key: &str, default: f64) -> Result<f64> {
+    match object.get(key) {
+        None => Ok(default),
+        Some(value) => value
+            .as_f64()
+            .filter(|number| number.is_finite())
+            .ok_or_else(|| eyre!("{key} must be a number")),
+    }
+}
+
+fn parse_signal(object: &Map<String, Value>, depth: usize) -> Result<Signal> {
+    let name = text_field(object, "name")?.unwrap_or_default();
+    let wave = text_field(object, "wave")?.unwrap_or_default();
+    let period = number_field(object, "period", 1.0)?;
+    if period <= 0.0 {
+        bail!("signal {name}: period must be positive");
+    }
+    let phase = number_field(object, "phase", 0.0)?;
+    let mut data = match object.get("data") {
+        None => Vec::new(),
+        Some(Value::String(data)) => data.split_whitespace().map(str::to_string).collect(),
+        Some(Value::Array(data)) => data
+            .iter()
+            .map(|label| match label {
+                Value::String(label) => Ok(label.clone()),
+                Value::Number(label) => Ok(label.to_string()),
+                _ => Err(eyre!("signal {name}: data labels must be plain text")),
+            })
+            .collect::<Result<_>>()?,
+        Some(_) => bail!("signal {name}: data must be a string or an array"),
+    }
+    .into_iter();
+
+    let mut runs: Vec<Run> = Vec::new();
+    let mut gaps = Vec::new();
+    for (index, c) in wave.chars().enumerate() {
+        let start = index as f64 * period - phase;
+        match c {
+            '.' | '|' => {
+                let run = runs
+                    .last_mut()
+                    .ok_or_else(|| eyre!("signal {name}: wave cannot start with {c}"))?;
+                run.length += period;
+                if c == '|' {
+                    gaps.push(start + period / 2.0);
+                }
+            }
+            c => {
+                let state = State::parse(c)
+                    .ok_or_else(|| eyre!("signal {name}: unsupported wave character {c}"))?;
+                let label = match state {
+                    State::Data(_) => data.next(),
+                    _ => None,
+                };
+                runs.push(Run {
+                    state,
+                    start,
+                    length: period,
+                    period,
+                    label,
+                });
+            }
+        }
+    }
+    Ok(Signal {
+        name,
+        depth,
+        runs,
+        gaps,
+    })
+}
+
+fn parse_lanes(entries: &[Value], depth: usize, lanes: &mut Vec<Lane>) -> Result<()> {
+    for entry in entries {
+        match entry {
+            Value::Object(object) if object.is_empty() => lanes.push(Lane::Spacer),
+            Value::Object(object) => lanes.push(Lane::Signal(parse_signal(object, depth)?)),
+            Value::Array(group) => {
+                let (label, members) = match group.split_first() {
+                    Some((Value::String(label), members)) => (label.clone(), members),
+                    _ => (String::new(), group.as_slice()),
+                };
+                if !label.is_empty() {
+                    lanes.push(Lane::Group(label, depth));
+                }
+                parse_lanes(members, depth + 1, lanes)?;
+            }
+            _ => bail!("signal entries must be objects or groups"),
+        }
+    }
+    Ok(())
+}
+
+fn arrow(x: f64, top: f64, up: bool) -> Element {
+    let (tip, base) = if up {
+        (top + MID - 4.0, top + MID + 2.0)
+    } else {
+        (top + MID + 4.0, top + MID - 2.0)
+    };
+    path("arrow", &[(x - 3.0, base), (x, tip), (x + 3.0, base)], true)
+}
+
+fn draw_signal(signal: &Signal, left: f64, top: f64, scale: f64, lane: &mut Element) {
+    let x = |ticks: f64| left + ticks * scale;
+    let mut previous: Option<f64> = None;
+    for run in &signal.runs {
+        let (x0, x1) = (x(run.start), x(run.start + run.length));
+        match run.state {
+            State::Low { sharp, arrow: up } | State::High { sharp, arrow: up } => {
+                let is_high = matches!(run.state, State::High { .. });
+                let y = top + if is_high { HIGH } else { LOW };
+                let slant = if sharp { 0.0 } else { SLANT };
+                let points = match previous {
+                    Some(py) if py != y => vec![(x0, py), (x0 + slant, y), (x1, y)],
+                    _ => vec![(x0, y), (x1, y)],
+                };
+                lane.children
+                    .push(XMLNode::Element(path("wave", &points, false)));
+                if up && previous.is_some_and(|py| py != y) {
+                    lane.children
+                        .push(XMLNode::Element(arrow(x0, top, is_high)));
+                }
+                previous = Some(y);
+            }
+            State::HighZ => {
+                let y = top + MID;
+                let points = match previous {
+                    Some(py) if py != y => vec![(x0, py), (x0 + SLANT, y), (x1, y)],
+                    _ => vec![(x0, y), (x1, y)],
+                };
+                lane.children
+                    .push(XMLNode::Element(path("wave", &points, false)));
+                previous = Some(y);
+            }
+            State::Clock {
+                positive,
+                arrow: up,
+            } => {
+                let (first, second) = if positive {
+                    (top + HIGH, top + LOW)
+                } else {
+                    (top + LOW, top + HIGH)
+                };
+                let width = run.period * scale;
+                let mut points = vec![(x0, previous.unwrap_or(second))];
+                let mut edge = x0;
+                while edge < x1 - 0.5 {
+                    points.extend([
+                        (edge, first),
+                        (edge + width / 2.0, first),
+                        (edge + width / 2.0, second),
+                        (edge + width, second),
+                    ]);
+                    if up {
+                        lane.children
+                            .push(XMLNode::Element(arrow(edge, top, positive)));
+                    }
+                    edge += width;
+                }
+                lane.children
+                    .push(XMLNode::Element(path("wave", &points, false)));
+                previous = Some(second);
+            }
+            State::Undefined | State::Data(_) => {
+                let class = match run.state {
+                    State::Data(colour) => format!("wave bus-{colour}"),
+                    _ => String::from("wave undefined"),
+                };
+                let points = [
+                    (x0, top + MID),
+                    (x0 + SLANT, top + HIGH),
+                    (x1 - SLANT, top + HIGH),
+                    (x1, top + MID),
+                    (x1 - SLANT, top + LOW),
+                    (x0 + SLANT, top + LOW),
+                ];
+                lane.children
+                    .push(XMLNode::Element(path(&class, &points, true)));
+                if let Some(label) = &run.label {
+                    lane.children.push(XMLNode::Element(text(
+                        (x0 + x1) / 2.0,
+                        top + MID,
+                        "label",
+                        "middle",
+                        label,
+                    )));
+                }
+                previous = None;
+            }
+        }
+    }
+    for gap in &signal.gaps {
+        let gap = x(*gap);
+        let (upper, lower) = (top + HIGH - 3.0, top + LOW + 3.0);
+        lane.children.push(XMLNode::Element(path(
+            "gap",
+            &[
+                (gap - 1.0, lower),
+                (gap + 3.0, upper),
+                (gap + 7.0, upper),
+                (gap + 3.0, lower),
+            ],
+            true,
+        )));
+    }
+}
+
+fn outline(lanes: &[Lane]) -> Option<String> {
+    let names = lanes
+        .iter()
+        .filter_map(|lane| match lane {
+            Lane::Signal(signal) if !signal.name.is_empty() => Some(signal.name.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    (!names.is_empty()).then(|| format!("Timing diagram of {}.", names.join(", ")))
+}
+
+fn parse_wavejson(source: &str) -> Result<Map<String, Value>> {
+    json5::from_str(source).map_err(|err| match err {
+        json5::Error::Message {
+            msg,
+            location: Some(location),
+        } => {
+            // pest renders the offending source above its message, so keep only the message
+            let message = msg
+                .lines()
+                .last()
+                .map(|line| line.trim().trim_start_matches("= ").to_string())
+                .unwrap_or(msg);
+            Diagnostics::single(location.line, location.column, message).into()
+        }
+        json5::Error::Message {
+            msg,
+            location: None,
+        } => eyre!(msg),
+    })
+}
+
+pub fn compile_wavedrom(
+    config: &SiteConfiguration,
+    source: &str,
+    options: &WaveOptions,
+) -> Result<String> {
+    let document = parse_wavejson(source)?;
+    for key in document.keys() {
+        if !matches!(key.as_str(), "signal" | "head" | "foot" | "config") {
+            bail!("{key} is not supported in timing diagrams");
+        }
+    }
+    let Some(Value::Array(signals)) = document.get("signal") else {
+        bail!("a timing diagram needs a signal array");
+    };
+    let mut lanes = Vec::new();
+    parse_lanes(signals, 0, &mut lanes)?;
+    let title = |key: &str| match document.get(key) {
+        Some(Value::Object(object)) => text_field(object, "text"),
+        Some(_) => Err(eyre!("{key} must be an object")),
+        None => Ok(None),
+    };
+    let (head, foot) = (title("head")?, title("foot")?);
+    let hscale = match document.get("config") {
+        Some(Value::Object(config)) => number_field(config, "hscale", 1.0)?,
+        Some(_) => bail!("config must be an object"),
+        None => 1.0,
+    };
+    if hscale <= 0.0 {
+        bail!("hscale must be positive");
+    }
+    let scale = TICK * hscale;
+
+    let names = lanes
+        .iter()
+        .map(|lane| match lane {
+            Lane::Signal(signal) => {
+                signal.depth as f64 * INDENT + text_width(&signal.name, FONT_SIZE)
+            }
+            Lane::Group(label, depth) => *depth as f64 * INDENT + text_width(label, FONT_SIZE),
+            Lane::Spacer => 0.0,
+        })
+        .fold(0.0, f64::max);
+    let left = MARGIN + names + MARGIN;
+    let ticks = lanes
+        .iter()
+        .filter_map(|lane| match lane {
+            Lane::Signal(signal) => signal.runs.last().map(|run| run.start + run.length),
+            _ => None,
+        })
+        .fold(0.0, f64::max);
+    let width = left + ticks * scale + MARGIN;
+    let mut top = MARGIN + if head.is_some() { ROW } else { 0.0 };
+    let height = top + lanes.len() as f64 * ROW + if foot.is_some() { ROW } else { 0.0 } + MARGIN;
+
+    let mut root = element(
+        "svg",
+        &[
+            ("xmlns", String::from("http://www.w3.org/2000/svg")),
+            ("width", width.to_string()),
+            ("height", height.to_string()),
+            ("viewBox", format!("0 0 {width} {height}")),
+        ],
+    );
+    let mut defs = element("defs", &[]);
+    let mut hatch = element(
+        "pattern",
+        &[
+            ("id", String::from("undefined")),
+            ("width", String::from("4")),
+            ("height", String::from("4")),
+            ("patternUnits", String::from("userSpaceOnUse")),
+        ],
+    );
+    hatch.children.push(XMLNode::Element(path(
+        "hatch",
+        &[(-1.0, 5.0), (5.0, -1.0)],
+        false,
+    )));
+    defs.children.push(XMLNode::Element(hatch));
+    let mut clip = element("clipPath", &[("id", String::from("lanes"))]);
+    clip.children.push(XMLNode::Element(element(
+        "rect",
+        &[
+            ("x", left.to_string()),
+            ("y", String::from("0")),
+            ("width", (ticks * scale).to_string()),
+            ("height", height.to_string()),
+        ],
+    )));
+    defs.children.push(XMLNode::Element(clip));
+    root.children.push(XMLNode::Element(defs));
+
+    if let Some(head) = &head {
+        root.children.push(XMLNode::Element(text(
+            width / 2.0,
+            MARGIN + ROW / 2.0,
+            "title",
+            "middle",
+            head,
+        )));
+    }
+    let mut waves = element("g", &[("clip-path", String::from("url(#lanes)"))]);
+    for lane in &lanes {
+        match lane {
+            Lane::Signal(signal) => {
+                root.children.push(XMLNode::Element(text(
+                    MARGIN + signal.depth as f64 * INDENT,
+                    top + MID,
+                    "name",
+                    "start",
+                    &signal.name,
+                )));
+                draw_signal(signal, left, top, scale, &mut waves);
+            }
+            Lane::Group(label, depth) => {
+                root.children.push(XMLNode::Element(text(
+                    MARGIN + *depth as f64 * INDENT,
+                    top + MID,
+                    "group",
+                    "start",
+                    label,
+                )));
+            }
+            Lane::Spacer => {}
+        }
+        top += ROW;
+    }
+    root.children.push(XMLNode::Element(waves));
+    if let Some(foot) = &foot {
+        root.children.push(XMLNode::Element(text(
+            width / 2.0,
+            top + ROW / 2.0,
+            "title",
+            "middle",
+            foot,
+        )));
+    }
+
+    let family = config.get_fontbook().regular_family()?;
+    root.children.push(XMLNode::Element(themed_style(|palette| {
+        let mut css = format!(
+            "text{{font-family:\"{family}\",sans-serif;font-size:{FONT_SIZE}px;fill:{}}}.title,.group{{font-weight:600}}\
+             .wave{{fill:none;stroke:{};stroke-width:1.5}}.hatch{{stroke:{};stroke-width:1}}\
+             .undefined{{fill:url(#undefined)}}.arrow{{fill:{}}}.gap{{fill:{};stroke:{}}}",
+            palette.text,
+            palette.stroke,
+            palette.grid,
+            palette.stroke,
+            palette.background,
+            palette.stroke
+        );
+        let fills = [palette.fill, palette.series[1], palette.series[2]];
+        for (colour, fill) in fills.iter().enumerate() {
+            css.push_str(&format!(".bus-{colour}{{fill:{fill}}}"));
+        }
+        css
+    })));
+    options.labels.apply(&mut root, |_| outline(&lanes));
+    let mut destination = Vec::new();
+    root.write(&mut destination)?;
+    Ok(String::from_utf8(destination)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(source: &str) -> Result<Signal> {
+        let Value::Object(object) = json5::from_str::<Value>(source)? else {
+            bail!("test signal is not an object");
+        };
+        parse_signal(&object, 0)
+    }
+
+    fn paths(signal: &Signal, left: f64, scale: f64) -> Vec<String> {
+        let mut lane = element("g", &[]);
+        draw_signal(signal, left, 0.0, scale, &mut lane);
+        lane.children
+            .iter()
+            .filter_map(XMLNode::as_element)
+            .filter_map(|path| path.attributes.get("d").cloned())
+            .collect()
+    }
+
+    #[test]
+    fn cycles_to_runs() -> Result<()> {
+        let signal = signal("{name: 'data', wave: 'x=.|=x', data: 'head body'}")?;
+        let runs = signal
+            .runs
+            .iter()
+            .map(|run| (run.start, run.length, run.label.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            runs,
+            [
+                (0.0, 1.0, None),
+                (1.0, 3.0, Some("head")),
+                (4.0, 1.0, Some("body")),
+                (5.0, 1.0, None)
+            ]
+        );
+        assert_eq!(signal.gaps, [3.5]);
+        Ok(())
+    }
+
+    #[test]
+    fn period_and_phase() -> Result<()> {
+        let signal = signal("{wave: '01.', period: 2, phase: 0.5}")?;
+        let runs = signal
+            .runs
+            .iter()
+            .map(|run| (run.start, run.length))
+            .collect::<Vec<_>>();
+        assert_eq!(runs, [(-0.5, 2.0), (1.5, 4.0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn cycles_to_x_positions() -> Result<()> {
+        // each cycle is scale wide from the left edge, and transitions slant into the new level
+        assert_eq!(
+            paths(&signal("{wave: '01.0'}")?, 100.0, 20.0),
+            [
+                "M100,25 L120,25",
+                "M120,25 L123,5 L160,5",
+                "M160,5 L163,25 L180,25"
+            ]
+        );
+        assert_eq!(
+            paths(&signal("{wave: 'lh'}")?, 0.0, 40.0),
+            ["M0,25 L40,25", "M40,25 L40,5 L80,5"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_waves() {
+        assert!(signal("{wave: '.1'}").is_err());
+        assert!(signal("{wave: '0q'}").is_err());
+        assert!(signal("{wave: '01', period: 0}").is_err());
+    }
+}